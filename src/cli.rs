@@ -0,0 +1,291 @@
+use anyhow::Context;
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::crypto::{token_id, token_key_id, CryptoState, KeyFile, KEY_FILE};
+use crate::data::{Accounting, ApiKeyCapability, Directory, DownloadCapability, UploadCapability};
+use crate::revocation::{RevocationList, REVOCATION_FILE};
+use crate::web::{upload_link, UploadLinkInfo};
+use crate::GenLinkArgs;
+
+pub fn gen_link(crypto: &CryptoState, args: GenLinkArgs) -> anyhow::Result<()> {
+    let cap = UploadCapability::new(args.name, args.size, args.valid_for)
+        .with_file_size_limit(args.max_file_size)
+        .with_file_count_limit(args.max_files)
+        .with_accepted_file_types(args.accept)
+        .with_activation_delay(args.opens_in);
+    cap.validate().map_err(anyhow::Error::msg)?;
+
+    let token = crypto.encrypt(&cap.to_string());
+    let link = upload_link(&args.base_url, &token);
+    if args.json {
+        let info = UploadLinkInfo::new(link, &token, &cap);
+        println!("{}", serde_json::to_string_pretty(&info)?);
+    } else {
+        println!("{link}");
+    }
+    Ok(())
+}
+
+pub fn gen_api_key(crypto: &CryptoState, label: String, valid_for: u64) -> anyhow::Result<()> {
+    let key = crypto.encrypt(&ApiKeyCapability::new(label, valid_for).to_string());
+    println!("{key}");
+    Ok(())
+}
+
+pub fn init_key_file(keep_legacy_links: bool) -> anyhow::Result<()> {
+    let path = Path::new(KEY_FILE);
+    KeyFile::generate(keep_legacy_links)
+        .save(path)
+        .with_context(|| format!("failed to create the key file {}", path.display()))?;
+    println!("key file {} created", path.display());
+    if !keep_legacy_links {
+        println!("links created before now are no longer valid");
+    }
+    Ok(())
+}
+
+pub fn revoke_link(crypto: &CryptoState, link: &str) -> anyhow::Result<()> {
+    let token = crypto
+        .find_token(link)
+        .ok_or_else(|| anyhow::anyhow!("no valid token found in the link"))?;
+    let id = token_id(token).map_err(anyhow::Error::msg)?;
+
+    let list = RevocationList::new(PathBuf::from(REVOCATION_FILE));
+    if list.revoke(&id)? {
+        println!("link {id} revoked");
+    } else {
+        println!("link {id} was already revoked");
+    }
+    Ok(())
+}
+
+pub fn inspect_link(
+    crypto: &CryptoState,
+    link: &str,
+    accounting: Accounting,
+) -> anyhow::Result<()> {
+    let token = crypto.find_token(link).ok_or_else(|| {
+        anyhow::anyhow!(
+            "no token in the link can be decrypted, it is damaged or was created with a different or retired secret"
+        )
+    })?;
+    let plaintext = crypto.decrypt(token).map_err(anyhow::Error::msg)?;
+    let id = token_id(token).map_err(anyhow::Error::msg)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    println!("token id:      {id}");
+    println!(
+        "signing key:   {}",
+        token_key_id(token).unwrap_or("(created before key rotation)")
+    );
+
+    let (dir_name, expiration, activation, size_limit) =
+        if let Ok(cap) = UploadCapability::from_str(&plaintext) {
+            println!("kind:          upload");
+            println!("directory:     {}", cap.dir_name());
+            println!("size limit:    {}", format_size(cap.size_limit()));
+            println!(
+                "file size:     {}",
+                cap.file_size_limit()
+                    .map(format_size)
+                    .unwrap_or_else(|| "unlimited".to_owned())
+            );
+            println!(
+                "file count:    {}",
+                cap.file_count_limit()
+                    .map(|c| c.to_string())
+                    .unwrap_or_else(|| "unlimited".to_owned())
+            );
+            println!(
+                "file types:    {}",
+                cap.accepted_file_types().unwrap_or("any")
+            );
+            if let Some(b) = cap.activation_timestamp() {
+                println!("opens:         {}", format_timestamp(b, now));
+            }
+            if let Err(err) = cap.validate() {
+                println!("invalid:       {err}");
+            }
+            (
+                cap.dir_name().to_owned(),
+                cap.expiration_timestamp(),
+                cap.activation_timestamp(),
+                Some(cap.size_limit()),
+            )
+        } else if let Ok(cap) = DownloadCapability::from_str(&plaintext) {
+            println!("kind:          download");
+            println!("directory:     {}", cap.dir_name());
+            if let Err(err) = cap.validate() {
+                println!("invalid:       {err}");
+            }
+            (
+                cap.dir_name().to_owned(),
+                cap.expiration_timestamp(),
+                None,
+                None,
+            )
+        } else {
+            anyhow::bail!("the token decrypts to an unknown capability: {plaintext}");
+        };
+    println!("expires:       {}", format_timestamp(expiration, now));
+
+    /* the directory must not be created when inspecting, so Directory::new can't be used */
+    let path = Path::new(&dir_name);
+    if path.is_dir() {
+        let used = Directory::calculate_existing_data_size(path, accounting)?;
+        let files = Directory::list_finished_files_in(path)?.len();
+        match size_limit {
+            Some(limit) => println!(
+                "usage:         {} of {} in {files} files",
+                format_size(used),
+                format_size(limit)
+            ),
+            None => println!("usage:         {} in {files} files", format_size(used)),
+        }
+    } else {
+        println!("usage:         nothing uploaded yet");
+    }
+
+    let revoked = RevocationList::new(PathBuf::from(REVOCATION_FILE)).is_revoked(&id);
+    let status = if revoked {
+        "revoked"
+    } else if expiration < now {
+        "expired"
+    } else if activation.map(|b| b > now).unwrap_or(false) {
+        "not active yet"
+    } else {
+        "valid"
+    };
+    println!("status:        {status}");
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["kB", "MB", "GB", "TB", "PB"];
+    if bytes < 1000 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64;
+    let mut unit = "B";
+    for u in UNITS {
+        if value < 1000.0 {
+            break;
+        }
+        value /= 1000.0;
+        unit = u;
+    }
+    format!("{value:.1} {unit} ({bytes} bytes)")
+}
+
+pub fn format_duration(secs: u64) -> String {
+    let (d, h, m) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    match (d, h, m) {
+        (0, 0, 0) => format!("{secs}s"),
+        (0, 0, m) => format!("{m}m"),
+        (0, h, m) => format!("{h}h {m}m"),
+        (d, h, _) => format!("{d}d {h}h"),
+    }
+}
+
+/// Year, month and day of the date the given number of days after the unix epoch,
+/// using the algorithm from http://howardhinnant.github.io/date_algorithms.html
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
+
+/// Formats a unix timestamp as a UTC date with the time relative to now
+fn format_timestamp(ts: u64, now: u64) -> String {
+    let (year, month, day) = civil_from_days((ts / 86400) as i64);
+    let secs = ts % 86400;
+
+    let relative = if ts >= now {
+        format!("in {}", format_duration(ts - now))
+    } else {
+        format!("{} ago", format_duration(now - ts))
+    };
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC ({relative})",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Splits a number from its unit suffix
+fn split_unit(s: &str) -> (&str, &str) {
+    let s = s.trim();
+    let idx = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    (&s[..idx], s[idx..].trim())
+}
+
+/// Parses sizes like `1000`, `500M`, `10G` or `4GiB`
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (num, unit) = split_unit(s);
+    let num: u64 = num.parse().map_err(|_| format!("invalid size: {s}"))?;
+    let multiplier: u64 = match unit.trim_end_matches(['B', 'b']) {
+        "" => 1,
+        "k" | "K" => 1_000,
+        "M" => 1_000_000,
+        "G" => 1_000_000_000,
+        "T" => 1_000_000_000_000,
+        "Ki" => 1 << 10,
+        "Mi" => 1 << 20,
+        "Gi" => 1 << 30,
+        "Ti" => 1 << 40,
+        _ => return Err(format!("unknown size unit: {unit}")),
+    };
+    num.checked_mul(multiplier)
+        .ok_or_else(|| format!("size too large: {s}"))
+}
+
+/// Parses durations like `3600`, `30m`, `12h` or `7d` into seconds
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    let (num, unit) = split_unit(s);
+    let num: u64 = num.parse().map_err(|_| format!("invalid duration: {s}"))?;
+    let multiplier: u64 = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 24 * 3600,
+        "w" => 7 * 24 * 3600,
+        _ => return Err(format!("unknown duration unit: {unit}")),
+    };
+    num.checked_mul(multiplier)
+        .ok_or_else(|| format!("duration too long: {s}"))
+}
+
+#[test]
+fn test_parse_units() {
+    assert_eq!(parse_size("1234"), Ok(1234));
+    assert_eq!(parse_size("500M"), Ok(500_000_000));
+    assert_eq!(parse_size("4GiB"), Ok(4 << 30));
+    assert!(parse_size("10X").is_err());
+    assert!(parse_size("M").is_err());
+    assert_eq!(parse_duration("90"), Ok(90));
+    assert_eq!(parse_duration("12h"), Ok(12 * 3600));
+    assert_eq!(parse_duration("7d"), Ok(7 * 24 * 3600));
+    assert!(parse_duration("1y").is_err());
+}
+
+#[test]
+fn test_format_timestamp() {
+    assert_eq!(format_timestamp(0, 90), "1970-01-01 00:00:00 UTC (1m ago)");
+    assert_eq!(
+        format_timestamp(1709210096, 1709210096 - 2 * 86400 - 3600),
+        "2024-02-29 12:34:56 UTC (in 2d 1h)"
+    );
+}
@@ -1,35 +1,139 @@
-use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use base64::{
+    engine::general_purpose::{URL_SAFE, URL_SAFE_NO_PAD},
+    Engine as _,
+};
 use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
 use rand::Rng;
 use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
 
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
 use std::str;
 
-#[derive(Clone, PartialEq, Eq)]
-pub struct CryptoState {
-    key: Vec<u8>,
+/// Separates the key identifier from the encrypted part of a token
+const KEY_ID_SEPARATOR: char = '.';
+
+/// File in the data root holding the per-deployment key derivation salt
+pub const KEY_FILE: &str = ".gimmedat-key";
+
+/// Salt used by all deployments before key files were introduced
+pub const LEGACY_SALT: &[u8] = b"fixedsaltforargon";
+
+/// Per-deployment key material, created by `gimmedat init`
+#[derive(Debug, PartialEq, Eq)]
+pub struct KeyFile {
+    pub salt: Vec<u8>,
+    /// Also accept links derived with the legacy fixed salt, used when migrating
+    /// an existing deployment so that the links it already handed out keep working
+    pub accept_legacy_links: bool,
 }
 
-impl CryptoState {
-    /// Fixed nonce. We don't need the property that the same message is encrypted
-    /// differently every single time. That would help prevent analysis if we would be
-    /// communicating with lots of similar messages. We are mainly using the cipher for
-    /// authentication and we want the message to be as short as possible.
+impl KeyFile {
+    pub fn generate(accept_legacy_links: bool) -> Self {
+        KeyFile {
+            salt: OsRng.gen::<[u8; 32]>().to_vec(),
+            accept_legacy_links,
+        }
+    }
+
+    /// Returns None when the file does not exist
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if std::fs::metadata(path)?.permissions().mode() & 0o077 != 0 {
+            log::warn!(
+                "key file {} is accessible by other users, it should have 0600 permissions",
+                path.display()
+            );
+        }
+
+        Self::parse(&content)
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn parse(content: &str) -> Result<Self, String> {
+        let mut salt = None;
+        let mut accept_legacy_links = false;
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some(("salt", value)) => {
+                    salt = Some(URL_SAFE.decode(value.trim()).map_err(|e| e.to_string())?)
+                }
+                Some(("accept-legacy-links", value)) => {
+                    accept_legacy_links = value.trim().parse().map_err(|_| {
+                        format!("invalid value of accept-legacy-links: {}", value.trim())
+                    })?
+                }
+                _ => return Err(format!("invalid line in the key file: {line}")),
+            }
+        }
+
+        match salt {
+            Some(salt) if salt.len() >= 16 => Ok(KeyFile {
+                salt,
+                accept_legacy_links,
+            }),
+            Some(_) => Err("the salt in the key file is too short".to_owned()),
+            None => Err("the key file does not contain a salt".to_owned()),
+        }
+    }
+
+    /// Writes the key file readable only by the owner, never overwrites an existing one
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        writeln!(file, "# gimmedat key file, keep it private and back it up.")?;
+        writeln!(file, "# Links stop working when it is lost or changed.")?;
+        writeln!(file, "salt={}", URL_SAFE.encode(&self.salt))?;
+        writeln!(
+            file,
+            "# remove this line once all links created before `gimmedat init` expired"
+        )?;
+        writeln!(file, "accept-legacy-links={}", self.accept_legacy_links)?;
+        file.sync_all()
+    }
+}
 
-    pub fn new(secret: &str) -> Self {
+#[derive(Clone)]
+struct SigningKey {
+    /// Short public fingerprint of the key, prefixed to every token
+    id: String,
+    key: Vec<u8>,
+}
+
+impl SigningKey {
+    fn derive(secret: &str, salt: &[u8]) -> Self {
         let pwd = secret.as_bytes();
-        let salt = b"fixedsaltforargon";
         let config = argon2::Config {
             variant: argon2::Variant::Argon2id,
             hash_length: 32,
             ..Default::default()
         };
         let key = argon2::hash_raw(pwd, salt, &config).unwrap();
-        CryptoState { key }
+
+        let mut hasher = Sha256::new();
+        hasher.update(b"gimmedat key id");
+        hasher.update(&key);
+        let id = URL_SAFE_NO_PAD.encode(&hasher.finalize()[..3]);
+
+        SigningKey { id, key }
     }
 
-    pub fn decrypt(&self, s: &str) -> Result<String, String> {
-        let bytes = URL_SAFE.decode(s).map_err(|err| err.to_string())?;
+    fn decrypt(&self, bytes: &[u8]) -> Result<String, String> {
         let nonce = Nonce::from_slice(&bytes[bytes.len() - 12..]);
         let ciphertext: &[u8] = &bytes[..bytes.len() - 12];
         let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.key));
@@ -40,19 +144,127 @@ impl CryptoState {
             .map_err(|err| err.to_string())
             .map(str::to_owned)
     }
+}
+
+/// Keys used for links. New links are always encrypted with the current key, the previous
+/// ones are only used for decrypting links created before the secret was rotated.
+#[derive(Clone)]
+pub struct CryptoState {
+    salt: Vec<u8>,
+    current: SigningKey,
+    previous: Vec<SigningKey>,
+}
+
+impl CryptoState {
+    /// The key derived from the secret becomes the current one, links are encrypted with it.
+    /// There are no previous keys yet, they are added when the secret was rotated.
+    pub fn new(secret: &str, salt: &[u8]) -> Self {
+        CryptoState {
+            salt: salt.to_vec(),
+            current: SigningKey::derive(secret, salt),
+            previous: Vec::new(),
+        }
+    }
+
+    /// Keeps accepting links created with the given retired secrets
+    pub fn with_previous_secrets(mut self, secrets: &[String]) -> Self {
+        let salt = &self.salt;
+        self.previous
+            .extend(secrets.iter().map(|s| SigningKey::derive(s, salt)));
+        self
+    }
+
+    /// Keeps accepting links created with the given secrets before the deployment had a key file
+    pub fn with_legacy_secrets(mut self, secrets: &[String]) -> Self {
+        self.previous
+            .extend(secrets.iter().map(|s| SigningKey::derive(s, LEGACY_SALT)));
+        self
+    }
+
+    /// Checks that the secret is the current one, previous secrets can't be used to create links
+    pub fn is_current_secret(&self, secret: &str) -> bool {
+        let key = SigningKey::derive(secret, &self.salt).key;
+        key.ct_eq(&self.current.key).into()
+    }
+
+    pub fn current_key_id(&self) -> &str {
+        &self.current.id
+    }
+
+    pub fn decrypt(&self, s: &str) -> Result<String, String> {
+        let (key_id, data) = split_token(s);
+        let bytes = URL_SAFE.decode(data).map_err(|err| err.to_string())?;
+        if bytes.len() < 12 {
+            return Err("token too short".to_owned());
+        }
+
+        let mut keys = std::iter::once(&self.current).chain(self.previous.iter());
+        match key_id {
+            Some(id) => keys
+                .find(|k| k.id == id)
+                .ok_or_else(|| "the link was signed with an unknown or retired key".to_owned())?
+                .decrypt(&bytes),
+            /* links created before key identifiers were introduced */
+            None => keys
+                .map(|k| k.decrypt(&bytes))
+                .find(Result::is_ok)
+                .unwrap_or_else(|| Err("invalid token".to_owned())),
+        }
+    }
 
     pub fn encrypt(&self, plaintext: &str) -> String {
         let nonce = Nonce::from(OsRng.gen::<[u8; 12]>());
-        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.key));
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.current.key));
         let mut ciphertext = cipher.encrypt(&nonce, plaintext.as_bytes()).unwrap();
         ciphertext.extend_from_slice(&nonce);
-        URL_SAFE.encode(ciphertext)
+        format!(
+            "{}{KEY_ID_SEPARATOR}{}",
+            self.current.id,
+            URL_SAFE.encode(ciphertext)
+        )
+    }
+
+    /// Finds the token in a full link (or a bare token) by trying to decrypt each path segment
+    pub fn find_token<'a>(&self, link: &'a str) -> Option<&'a str> {
+        link.split(['/', '?', '#'])
+            .filter(|s| !s.is_empty())
+            .find(|s| self.decrypt(s).is_ok())
+    }
+}
+
+/// Identifier of a token, which can be shown and stored without revealing the token itself.
+/// It's the random nonce, so it's unique for every generated link and can't be changed
+/// without breaking the authentication.
+pub fn token_id(token: &str) -> Result<String, String> {
+    let (_, data) = split_token(token);
+    let bytes = URL_SAFE.decode(data).map_err(|err| err.to_string())?;
+    if bytes.len() < 12 {
+        return Err("token too short".to_owned());
+    }
+    Ok(URL_SAFE_NO_PAD.encode(&bytes[bytes.len() - 12..]))
+}
+
+/// Compares secret values without leaking the position of the first difference through timing
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Identifier of the key the token was encrypted with, None for tokens created before key rotation
+pub fn token_key_id(token: &str) -> Option<&str> {
+    split_token(token).0
+}
+
+/// Splits the token into the optional key identifier and the encrypted data
+fn split_token(token: &str) -> (Option<&str>, &str) {
+    match token.split_once(KEY_ID_SEPARATOR) {
+        Some((id, data)) => (Some(id), data),
+        None => (None, token),
     }
 }
 
 #[test]
 fn test_reversability() {
-    let c = CryptoState::new("secretkey");
+    let c = CryptoState::new("secretkey", LEGACY_SALT);
     const PLAIN: &str = "some text which is not really long but not short either";
     let new_plain = c.decrypt(&c.encrypt(PLAIN)).expect("failed decryption");
     assert_eq!(PLAIN, new_plain);
@@ -60,7 +272,57 @@ fn test_reversability() {
 
 #[test]
 fn test_encrypted_twice_with_different_results() {
-    let c = CryptoState::new("secretkey");
+    let c = CryptoState::new("secretkey", LEGACY_SALT);
     const PLAIN: &str = "plaintext";
     assert_ne!(c.encrypt(PLAIN), c.encrypt(PLAIN));
 }
+
+#[test]
+fn test_find_token_in_link() {
+    let c = CryptoState::new("secretkey", LEGACY_SALT);
+    let token = c.encrypt("d=dir&s=1&t=1");
+    let link = format!("https://example.org/dl/{token}/file.txt");
+    assert_eq!(c.find_token(&link), Some(token.as_str()));
+    assert_eq!(c.find_token("https://example.org/abcd/"), None);
+    assert_eq!(token_id(&token).unwrap().len(), 16);
+}
+
+#[test]
+fn test_key_rotation() {
+    let old = CryptoState::new("oldsecret", LEGACY_SALT);
+    let old_token = old.encrypt("plaintext");
+    let (_, legacy_token) = split_token(&old_token);
+
+    let new =
+        CryptoState::new("newsecret", LEGACY_SALT).with_previous_secrets(&["oldsecret".to_owned()]);
+    assert_eq!(new.decrypt(&old_token).unwrap(), "plaintext");
+    assert_eq!(new.decrypt(legacy_token).unwrap(), "plaintext");
+    assert!(new.encrypt("x").starts_with(new.current_key_id()));
+    assert!(new.is_current_secret("newsecret"));
+    assert!(!new.is_current_secret("oldsecret"));
+
+    let retired = CryptoState::new("newsecret", LEGACY_SALT);
+    assert!(retired.decrypt(&old_token).is_err());
+    assert!(retired.decrypt(legacy_token).is_err());
+}
+
+#[test]
+fn test_key_file_salt() {
+    let key_file = KeyFile::generate(true);
+    let content = format!(
+        "# comment\nsalt={}\naccept-legacy-links=true\n",
+        URL_SAFE.encode(&key_file.salt)
+    );
+    assert_eq!(KeyFile::parse(&content).unwrap(), key_file);
+    assert!(KeyFile::parse("accept-legacy-links=true").is_err());
+
+    let legacy = CryptoState::new("secretkey", LEGACY_SALT);
+    let legacy_token = legacy.encrypt("plaintext");
+    let salted = CryptoState::new("secretkey", &key_file.salt);
+    assert_ne!(salted.current_key_id(), legacy.current_key_id());
+    assert!(salted.decrypt(&legacy_token).is_err());
+
+    let migrated = salted.with_legacy_secrets(&["secretkey".to_owned()]);
+    assert_eq!(migrated.decrypt(&legacy_token).unwrap(), "plaintext");
+    assert!(migrated.is_current_secret("secretkey"));
+}
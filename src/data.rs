@@ -1,20 +1,32 @@
 use async_std::fs::File;
 use async_std::fs::OpenOptions;
 use async_std::sync::Mutex;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
 use log::error;
+use log::info;
 use log::warn;
+use rand::Rng;
+use rand_core::OsRng;
 use serde_derive::{Deserialize, Serialize};
 
+use crate::quota::StorageQuota;
+use crate::watcher::DirectoryWatcher;
+
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::ffi::OsStr;
 use std::ffi::OsString;
+use std::fmt::Display;
 use std::fs::read_dir;
 use std::fs::DirBuilder;
+use std::fs::DirEntry;
+use std::fs::Metadata;
 use std::os::unix::prelude::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
 use std::pin::Pin;
 use std::str;
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
@@ -38,6 +50,18 @@ pub struct UploadCapability {
     s: u64,
     /// timeout (unix timestamp)
     t: u64,
+    /// size limit of a single file in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    f: Option<u64>,
+    /// maximum number of files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    c: Option<u64>,
+    /// allowed file types, same format as the accept attribute of HTML file inputs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<String>,
+    /// activation time, no uploads are allowed before it (unix timestamp)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    b: Option<u64>,
 }
 
 impl UploadCapability {
@@ -45,17 +69,143 @@ impl UploadCapability {
         self.s
     }
 
+    pub fn file_size_limit(&self) -> Option<u64> {
+        self.f
+    }
+
+    pub fn file_count_limit(&self) -> Option<u64> {
+        self.c
+    }
+
     pub fn new(dir_name: String, maxsize: u64, validity_duration: u64) -> Self {
         UploadCapability {
             d: dir_name,
             s: maxsize,
-            t: current_unix_timestamp() + validity_duration,
+            t: current_unix_timestamp().saturating_add(validity_duration),
+            f: None,
+            c: None,
+            a: None,
+            b: None,
+        }
+    }
+
+    pub fn with_file_size_limit(mut self, limit: Option<u64>) -> Self {
+        self.f = limit;
+        self
+    }
+
+    pub fn with_file_count_limit(mut self, limit: Option<u64>) -> Self {
+        self.c = limit;
+        self
+    }
+
+    /// Checks whether a file of the given size is allowed by the per-file limit
+    pub fn allows_file_size(&self, size: u64) -> bool {
+        self.f.map(|limit| size <= limit).unwrap_or(true)
+    }
+
+    /// Delays the activation of the link. The validity period then starts at the activation time.
+    pub fn with_activation_delay(mut self, delay: Option<u64>) -> Self {
+        if let Some(delay) = delay {
+            self.b = Some(current_unix_timestamp().saturating_add(delay));
+            self.t = self.t.saturating_add(delay);
+        }
+        self
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.b
+            .map(|b| b <= current_unix_timestamp())
+            .unwrap_or(true)
+    }
+
+    /// Time when the link starts accepting uploads (unix timestamp), if it was delayed
+    pub fn activation_timestamp(&self) -> Option<u64> {
+        self.b
+    }
+
+    /// Seconds until the link starts accepting uploads, zero when already active
+    pub fn time_until_active_secs(&self) -> u64 {
+        self.b
+            .map(|b| b.saturating_sub(current_unix_timestamp()))
+            .unwrap_or(0)
+    }
+
+    pub fn accepted_file_types(&self) -> Option<&str> {
+        self.a.as_deref()
+    }
+
+    pub fn with_accepted_file_types(mut self, types: Option<String>) -> Self {
+        self.a = types;
+        self
+    }
+
+    fn accepted_file_type_entries(&self) -> impl Iterator<Item = &str> {
+        self.a
+            .iter()
+            .flat_map(|a| a.split(','))
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+    }
+
+    /// Checks the file name against the allowlist. Extension entries (`.jpg`) are matched
+    /// directly, MIME entries (`image/png`, `video/*`) by the type guessed from the name.
+    pub fn allows_file_name(&self, file_name: &str) -> bool {
+        self.a.is_none()
+            || self.allowed_by_extension(file_name)
+            || mime_guess::from_path(file_name)
+                .iter()
+                .any(|m| self.allowed_by_mime(m.essence_str()))
+    }
+
+    /// Checks a file against the allowlist, using the name and the type detected from the
+    /// content. When the content was recognized, it must be of a type the name suggests,
+    /// so that a disallowed file can't get through just by being renamed. Files allowed only
+    /// by a MIME entry are rejected when the content is not recognized.
+    pub fn allows_file_type(&self, file_name: &str, sniffed_mime: Option<&str>) -> bool {
+        if self.a.is_none() {
+            return true;
+        }
+        if !self.allows_file_name(file_name) {
+            return false;
+        }
+
+        match sniffed_mime {
+            /* only the container is known, the name allowed above tells the rest */
+            Some(sniffed) if same_container(sniffed, file_name) => true,
+            Some(sniffed) => {
+                let matches_name = mime_guess::from_path(file_name)
+                    .iter()
+                    .any(|m| m.essence_str().eq_ignore_ascii_case(sniffed));
+                matches_name
+                    && (self.allowed_by_extension(file_name) || self.allowed_by_mime(sniffed))
+            }
+            None => self.allowed_by_extension(file_name),
         }
     }
 
+    fn allowed_by_extension(&self, file_name: &str) -> bool {
+        let Some(extension) = Path::new(file_name).extension().and_then(|e| e.to_str()) else {
+            return false;
+        };
+        self.accepted_file_type_entries()
+            .filter_map(|entry| entry.strip_prefix('.'))
+            .any(|allowed| allowed.eq_ignore_ascii_case(extension))
+    }
+
+    fn allowed_by_mime(&self, mime: &str) -> bool {
+        self.accepted_file_type_entries()
+            .filter(|e| !e.starts_with('.'))
+            .any(|e| mime_matches(e, mime))
+    }
+
     pub fn validate(&self) -> Result<(), &'static str> {
-        if self.d.contains('/') {
-            return Err("the given path contains invalid characters");
+        validate_dir_name(&self.d)?;
+        if self
+            .accepted_file_type_entries()
+            .any(|e| !e.starts_with('.') && !e.contains('/'))
+        {
+            return Err("the allowed file types must be extensions or MIME types");
         }
 
         Ok(())
@@ -81,6 +231,10 @@ impl UploadCapability {
         }
     }
 
+    pub fn expiration_timestamp(&self) -> u64 {
+        self.t
+    }
+
     /// Works properly only when not expired
     pub fn remaining_time_secs(&self) -> u64 {
         assert!(!self.is_expired());
@@ -92,9 +246,9 @@ impl UploadCapability {
     }
 }
 
-impl ToString for UploadCapability {
-    fn to_string(&self) -> String {
-        serde_urlencoded::to_string(self).unwrap()
+impl Display for UploadCapability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", serde_urlencoded::to_string(self).unwrap())
     }
 }
 
@@ -104,123 +258,1042 @@ impl From<UploadCapability> for String {
     }
 }
 
+/// Capability granting access to the JSON admin API, so that scripts don't need the secret.
+/// Unknown fields are rejected, so that it can't be confused with the other capabilities.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyCapability {
+    /// label of the key, used only in logs
+    n: String,
+    /// timeout (unix timestamp)
+    t: u64,
+}
+
+impl ApiKeyCapability {
+    pub fn new(label: String, validity_duration: u64) -> Self {
+        ApiKeyCapability {
+            n: label,
+            t: current_unix_timestamp().saturating_add(validity_duration),
+        }
+    }
+
+    pub fn from_str(source: &str) -> Result<Self, impl std::error::Error> {
+        serde_urlencoded::from_str(source)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.t < current_unix_timestamp()
+    }
+
+    pub fn label(&self) -> &str {
+        &self.n
+    }
+}
+
+impl Display for ApiKeyCapability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", serde_urlencoded::to_string(self).unwrap())
+    }
+}
+
+/// Admin session stored in a cookie. The random CSRF token has to be sent back with every form
+/// changing the state, so that other sites can't submit forms on behalf of a logged in admin.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SessionCapability {
+    /// timeout (unix timestamp)
+    t: u64,
+    /// CSRF token
+    x: String,
+}
+
+impl SessionCapability {
+    pub fn new(validity_duration: u64) -> Self {
+        SessionCapability {
+            t: current_unix_timestamp().saturating_add(validity_duration),
+            x: URL_SAFE_NO_PAD.encode(OsRng.gen::<[u8; 16]>()),
+        }
+    }
+
+    pub fn from_str(source: &str) -> Result<Self, impl std::error::Error> {
+        serde_urlencoded::from_str(source)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.t < current_unix_timestamp()
+    }
+
+    pub fn csrf_token(&self) -> &str {
+        &self.x
+    }
+}
+
+impl Display for SessionCapability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", serde_urlencoded::to_string(self).unwrap())
+    }
+}
+
+/// Read-only capability allowing to list and download finished files of a directory.
+/// Unknown fields are rejected, so that an upload capability can never be used as a download one.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DownloadCapability {
+    /// dir name with the data
+    d: String,
+    /// timeout (unix timestamp)
+    t: u64,
+}
+
+impl DownloadCapability {
+    pub fn new(dir_name: String, validity_duration: u64) -> Self {
+        DownloadCapability {
+            d: dir_name,
+            t: current_unix_timestamp().saturating_add(validity_duration),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        validate_dir_name(&self.d)
+    }
+
+    pub fn from_str(source: &str) -> Result<Self, impl std::error::Error> {
+        serde_urlencoded::from_str(source)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.t < current_unix_timestamp()
+    }
+
+    pub fn expiration_timestamp(&self) -> u64 {
+        self.t
+    }
+
+    /// Works properly only when not expired
+    pub fn remaining_time_secs(&self) -> u64 {
+        assert!(!self.is_expired());
+        self.t - current_unix_timestamp()
+    }
+
+    pub fn dir_name(&self) -> &str {
+        &self.d
+    }
+}
+
+impl Display for DownloadCapability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", serde_urlencoded::to_string(self).unwrap())
+    }
+}
+
+/// Identification of a resumable (tus) upload. It's encrypted the same way as
+/// the capability and handed to the client as the upload URL, so that the server
+/// does not have to store the announced length anywhere. It's valid only together
+/// with the link it was created with.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ResumableUpload {
+    /// directory name
+    d: String,
+    /// id of the token of the link
+    k: String,
+    /// file name
+    n: String,
+    /// total length of the upload in bytes
+    l: u64,
+}
+
+impl ResumableUpload {
+    pub fn new(dir_name: String, token_id: String, file_name: String, length: u64) -> Self {
+        ResumableUpload {
+            d: dir_name,
+            k: token_id,
+            n: file_name,
+            l: length,
+        }
+    }
+
+    /// Whether the upload was created with the link of the token
+    pub fn belongs_to(&self, dir_name: &str, token_id: &str) -> bool {
+        self.d == dir_name && self.k == token_id
+    }
+
+    pub fn from_str(source: &str) -> Result<Self, impl std::error::Error> {
+        serde_urlencoded::from_str(source)
+    }
+
+    pub fn file_name(&self) -> &str {
+        &self.n
+    }
+
+    pub fn length(&self) -> u64 {
+        self.l
+    }
+}
+
+impl Display for ResumableUpload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", serde_urlencoded::to_string(self).unwrap())
+    }
+}
+
+/// Formats built on a common container, as (types detected from the content, extensions). The
+/// content sniffing can tell only the container for many of them and it mistakes some members
+/// for others, e.g. every OLE file is reported as a Word document.
+const CONTAINERS: &[(&[&str], &[&str])] = &[
+    (
+        &[
+            "application/zip",
+            "application/epub+zip",
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+            /* sic, reported for all .pptx files */
+            "application/application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        ],
+        &[
+            "zip", "docx", "docm", "dotx", "xlsx", "xlsm", "xltx", "pptx", "pptm", "potx", "ppsx",
+            "odt", "ods", "odp", "odg", "odf", "ott", "ots", "otp", "epub", "jar", "war", "apk",
+            "xpi", "vsdx", "xps", "kmz", "cbz", "3mf", "ipa", "whl", "nupkg", "appx",
+        ],
+    ),
+    (
+        &[
+            "application/msword",
+            "application/vnd.ms-excel",
+            "application/vnd.ms-powerpoint",
+            "application/x-ole-storage",
+        ],
+        &[
+            "doc", "dot", "xls", "xlt", "xla", "ppt", "pot", "pps", "msg", "msi", "vsd", "pub",
+            "mpp",
+        ],
+    ),
+];
+
+/// Whether the sniffed type is a container the format of the file name is built on
+fn same_container(sniffed: &str, file_name: &str) -> bool {
+    let Some(extension) = Path::new(file_name).extension().and_then(|e| e.to_str()) else {
+        return false;
+    };
+    CONTAINERS.iter().any(|(sniffed_types, extensions)| {
+        sniffed_types
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(sniffed))
+            && extensions.iter().any(|e| e.eq_ignore_ascii_case(extension))
+    })
+}
+
+/// Matches a MIME type against a pattern which can have a wildcard subtype (`image/*`)
+fn mime_matches(pattern: &str, mime: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(basetype) => mime
+            .split('/')
+            .next()
+            .map(|b| b.eq_ignore_ascii_case(basetype))
+            .unwrap_or(false),
+        None => pattern.eq_ignore_ascii_case(mime),
+    }
+}
+
+/// Renames a file, but never replaces an existing one. On filesystems which can't do that
+/// atomically, the target is checked first, the callers hold the name lock against own uploads.
+fn rename_noreplace(from: &Path, to: &Path) -> std::io::Result<()> {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    {
+        use nix::errno::Errno;
+        use nix::fcntl::{renameat2, RenameFlags};
+
+        match renameat2(None, from, None, to, RenameFlags::RENAME_NOREPLACE) {
+            /* the filesystem doesn't support the flag */
+            Err(Errno::EINVAL | Errno::ENOSYS) => {}
+            res => return res.map_err(std::io::Error::from),
+        }
+    }
+
+    if std::fs::symlink_metadata(to).is_ok() {
+        return Err(std::io::ErrorKind::AlreadyExists.into());
+    }
+    std::fs::rename(from, to)
+}
+
+/// Directory names starting with a dot are reserved for the server's own state
+pub fn validate_dir_name(name: &str) -> Result<(), &'static str> {
+    if name.contains('/') {
+        return Err("the given path contains invalid characters");
+    }
+    if name.is_empty() || name.starts_with('.') {
+        return Err("the given directory name is not allowed");
+    }
+
+    Ok(())
+}
+
+/// Checks that a client supplied file name does not escape the target directory
+pub fn validate_file_name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err("invalid file name");
+    }
+    if name == STAGING_DIR || name.ends_with(LEGACY_PARTIAL_SUFFIX) {
+        return Err("the file name is reserved");
+    }
+    if name.contains('/') || name.contains('\0') {
+        return Err("the file name contains invalid characters");
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum FileError {
+    /// a file with the same name was already uploaded or is being uploaded
+    AlreadyExists,
+    /// there is no unfinished upload with the given name
+    NotFound,
+    /// another request is writing into the file right now
+    Busy,
+    /// the client and the server disagree on how much data was already stored
+    OffsetMismatch { expected: u64, actual: u64 },
+    /// the maximum number of files allowed by the link was reached
+    TooManyFiles { limit: u64 },
+    /// not even an empty file fits into the data limit
+    DataLimitExceeded,
+    /// the server-wide storage limit or the free space reserve would be violated
+    InsufficientStorage,
+    /// the filesystem failed
+    Io(std::io::Error),
+}
+
+impl Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileError::AlreadyExists => write!(f, "file already exists"),
+            FileError::NotFound => write!(f, "upload not found"),
+            FileError::Busy => write!(f, "the upload is in progress in another request"),
+            FileError::OffsetMismatch { expected, actual } => write!(
+                f,
+                "upload offset mismatch, the server has {actual} bytes, but the request starts at {expected}"
+            ),
+            FileError::TooManyFiles { limit } => {
+                write!(f, "file count limit reached, at most {limit} files are allowed")
+            }
+            FileError::DataLimitExceeded => write!(f, "data limit exceeded"),
+            FileError::InsufficientStorage => {
+                write!(f, "not enough storage space left on the server")
+            }
+            FileError::Io(e) => write!(f, "I/O error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for FileError {}
+
+/// A partial file of an upload which was not finished (yet)
+pub struct PartialUpload {
+    pub name: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Overview of a directory for the admin dashboard, computed from the disk
+pub struct DirectorySummary {
+    pub name: String,
+    pub files: usize,
+    pub total_bytes: u64,
+    pub last_upload: Option<SystemTime>,
+    pub partial_uploads: Vec<PartialUpload>,
+}
+
+/// Hidden directory inside every upload directory holding the files of unfinished uploads.
+/// Being on the same filesystem, finished files can be moved out of it atomically.
+pub const STAGING_DIR: &str = ".gimmedat-partial";
+
+/// Older versions stored unfinished uploads next to the finished ones with this suffix
+const LEGACY_PARTIAL_SUFFIX: &str = "$.partial";
+
+/// How the bytes used by a directory are counted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Accounting {
+    /// file sizes plus 4096 bytes and the name length per file for the metadata
+    #[default]
+    Apparent,
+    /// space allocated on the disk, the same as `du` reports
+    Allocated,
+}
+
+impl Accounting {
+    /// Bytes charged for an existing file
+    pub fn charge(self, meta: &Metadata, name_len: usize) -> u64 {
+        match self {
+            Accounting::Apparent => {
+                meta.size() // file content
+                    + name_len as u64 // length of the file name
+                    + 4096 // constant overhead to account for metadata space of empty files
+            }
+            Accounting::Allocated => meta.blocks() * 512,
+        }
+    }
+
+    /// Bytes charged for a newly created empty file
+    fn empty_file_charge(self, name_len: usize) -> u64 {
+        match self {
+            Accounting::Apparent => 4096 + name_len as u64,
+            Accounting::Allocated => 0,
+        }
+    }
+}
+
 pub struct Directory {
     path: PathBuf,
+    accounting: Accounting,
+    /// block size of the filesystem, writes are rounded up to it in the allocated accounting
+    block_size: u64,
     real_size: AtomicU64,
+    /// every change of `real_size` is reported to the server-wide quota as well
+    quota: Arc<StorageQuota>,
     filenames: Mutex<HashSet<OsString>>,
+    /// names of files with a writer currently open
+    writers: Mutex<HashSet<OsString>>,
+    /// the directory changed during an upload, the bytes are recounted after the last one finishes
+    recount_pending: AtomicBool,
+    /// the directory was removed from the disk, a new one is created for further uploads
+    removed: AtomicBool,
 }
 
 impl Directory {
-    pub fn new(path: PathBuf) -> anyhow::Result<Self> {
-        Self::ensure_path_existence(&path);
+    pub fn new(
+        path: PathBuf,
+        accounting: Accounting,
+        quota: Arc<StorageQuota>,
+    ) -> anyhow::Result<Self> {
+        /* older versions had no staging directory, their partial files are moved there only once, when it's created */
+        let legacy = !Self::staging_path(&path).is_dir();
+        Self::create_staging_dir(&path)?;
+        if legacy {
+            Self::adopt_legacy_partial_files(&path)?;
+        }
 
-        let size = AtomicU64::new(Self::calculate_existing_data_size(&path)?);
+        let size = AtomicU64::new(Self::calculate_existing_data_size(&path, accounting)?);
         let filenames = Mutex::new(Self::create_filename_set(&path)?);
+        /* filesystems with the smallest blocks use 512 bytes */
+        let block_size = std::fs::metadata(&path)?.blksize().max(512);
+        quota.load(&path, size.load(Ordering::Relaxed));
 
         Ok(Self {
             path,
+            accounting,
+            block_size,
             real_size: size,
+            quota,
             filenames,
+            writers: Mutex::new(HashSet::new()),
+            recount_pending: AtomicBool::new(false),
+            removed: AtomicBool::new(false),
         })
     }
 
-    fn ensure_path_existence(path: &Path) {
-        if !(path.exists() && path.is_dir()) {
-            DirBuilder::new()
-                .recursive(true)
-                .create(path)
-                .expect("creating directory should never fail");
+    /// Creates the directory with its staging directory, unless they exist already
+    fn create_staging_dir(path: &Path) -> std::io::Result<()> {
+        DirBuilder::new()
+            .recursive(true)
+            .create(Self::staging_path(path))
+    }
+
+    pub fn staging_path(path: &Path) -> PathBuf {
+        path.join(STAGING_DIR)
+    }
+
+    /// Older versions stored unfinished uploads next to the finished ones as `name$.partial`,
+    /// move them into the staging directory so that they can still be resumed
+    fn adopt_legacy_partial_files(path: &Path) -> anyhow::Result<()> {
+        for entry in read_dir(path)?.flatten() {
+            let file_name = entry.file_name();
+            let Some(name) = file_name
+                .to_str()
+                .and_then(|n| n.strip_suffix(LEGACY_PARTIAL_SUFFIX))
+            else {
+                continue;
+            };
+            let target = Self::staging_path(path).join(name);
+            if validate_file_name(name).is_err() || target.exists() {
+                continue;
+            }
+            std::fs::rename(entry.path(), target)?;
+            info!(
+                "Moved unfinished upload {:?} into the staging directory",
+                entry.path()
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether there are any unfinished uploads, checked without loading the directory
+    pub fn has_partial_files(path: &Path) -> bool {
+        read_dir(Self::staging_path(path))
+            .map(|mut entries| entries.next().is_some())
+            .unwrap_or(false)
+    }
+
+    /// Entries of the staging directory, which does not have to exist yet
+    fn staged_entries(path: &Path) -> std::io::Result<Vec<DirEntry>> {
+        match read_dir(Self::staging_path(path)) {
+            Ok(entries) => Ok(entries.flatten().collect()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Names of finished files and of unfinished uploads, paused resumable uploads keep
+    /// their names claimed even after a restart
+    fn create_filename_set(path: &Path) -> anyhow::Result<HashSet<OsString>> {
+        let staged = Self::staged_entries(path)?;
+        Ok(read_dir(path)?
+            .map(|e| e.unwrap())
+            .chain(staged)
+            .map(|e| e.file_name())
+            .filter(|name| name != STAGING_DIR)
+            .collect())
+    }
+
+    pub fn calculate_existing_data_size(dir: &Path, accounting: Accounting) -> anyhow::Result<u64> {
+        let mut size = 0u64;
+        let staged = Self::staged_entries(dir)?;
+        for dir in read_dir(dir)?.flatten().chain(staged) {
+            if dir.file_name() == STAGING_DIR {
+                continue;
+            }
+            size += accounting.charge(&dir.metadata().unwrap(), dir.file_name().len());
+        }
+
+        Ok(size)
+    }
+
+    pub async fn create_file_writer<'a>(
+        &'a self,
+        uc: &UploadCapability,
+        filename: &'a str,
+        expected_size: Option<u64>,
+    ) -> Result<DirectoryFileWriter<'a>, FileError> {
+        let partial_name = self.get_partial_file_name(filename);
+
+        let filename_os = OsString::from(filename);
+
+        /* lock the filename we are working on */
+        let slack = {
+            /* check for finished file name collision & claim it if it's free */
+            let mut names = self.filenames.lock().await;
+            if names.contains(&filename_os) {
+                return Err(FileError::AlreadyExists);
+            }
+            Self::check_file_count(&names, uc)?;
+            let slack = self.write_slack(expected_size.unwrap_or(0), uc.size_limit());
+            self.charge_new_partial_file(filename, slack, uc.size_limit())?;
+            names.insert(filename_os.clone());
+            self.writers.lock().await.insert(filename_os);
+            slack
+        };
+
+        /* there is still a possibility that a partial file exists, however we can
+        be certain that we are not writing into it at the moment, so lets just ignore it
+        and rewrite it (its bytes were released above) */
+
+        /* create partial file writer, the staging directory might have been removed in the meantime */
+        let initial_charge = self.accounting.empty_file_charge(filename.len());
+        let opened = match Self::create_staging_dir(&self.path) {
+            Ok(()) => {
+                OpenOptions::new()
+                    .create(true) // this allows rewrites
+                    .truncate(true)
+                    .write(true)
+                    .open(partial_name)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        let file = match opened {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Error creating a partial file: {e}");
+                let filename_os = OsString::from(filename);
+                let mut names = self.filenames.lock().await;
+                self.writers.lock().await.remove(&filename_os);
+                names.remove(&filename_os);
+                self.give_up_partial_file(filename, initial_charge + slack);
+                return Err(FileError::Io(e));
+            }
+        };
+
+        /* create file writer object */
+        Ok(DirectoryFileWriter::new(
+            self,
+            file,
+            uc.size_limit(),
+            filename,
+            expected_size,
+            uc.expiration_time(),
+        )
+        .limit_file_size(uc.file_size_limit())
+        .charged(initial_charge, slack))
+    }
+
+    /// Claims the file name and creates an empty partial file, which can be
+    /// filled later in any number of requests using [`Directory::resume_file_writer`]
+    pub async fn create_resumable_file(
+        &self,
+        uc: &UploadCapability,
+        filename: &str,
+    ) -> Result<(), FileError> {
+        let filename_os = OsString::from(filename);
+        let mut names = self.filenames.lock().await;
+        if names.contains(&filename_os) {
+            return Err(FileError::AlreadyExists);
+        }
+        Self::check_file_count(&names, uc)?;
+        self.charge_new_partial_file(filename, 0, uc.size_limit())?;
+
+        let created = match Self::create_staging_dir(&self.path) {
+            Ok(()) => File::create(self.get_partial_file_name(filename)).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = created {
+            error!("Error creating a partial file: {e}");
+            self.give_up_partial_file(filename, self.accounting.empty_file_charge(filename.len()));
+            return Err(FileError::Io(e));
+        }
+        names.insert(filename_os);
+
+        Ok(())
+    }
+
+    /// Returns the number of bytes already stored for an unfinished upload
+    pub async fn get_resumable_offset(&self, filename: &str) -> Result<u64, FileError> {
+        let _names = self.filenames.lock().await;
+        self.partial_file_size(filename)
+    }
+
+    fn partial_file_size(&self, filename: &str) -> Result<u64, FileError> {
+        self.partial_file_metadata(filename).map(|meta| meta.len())
+    }
+
+    fn partial_file_metadata(&self, filename: &str) -> Result<Metadata, FileError> {
+        match std::fs::metadata(self.get_partial_file_name(filename)) {
+            Ok(meta) => Ok(meta),
+            Err(_) if self.get_final_file_name(filename).exists() => Err(FileError::AlreadyExists),
+            Err(_) => Err(FileError::NotFound),
+        }
+    }
+
+    /// Opens an unfinished upload for appending. The offset must match the amount
+    /// of data already stored on disk.
+    pub async fn resume_file_writer<'a>(
+        &'a self,
+        uc: &UploadCapability,
+        filename: &'a str,
+        offset: u64,
+        total_size: u64,
+    ) -> Result<DirectoryFileWriter<'a>, FileError> {
+        let filename_os = OsString::from(filename);
+
+        let (initial_charge, slack) = {
+            let mut names = self.filenames.lock().await;
+            let mut writers = self.writers.lock().await;
+            if writers.contains(&filename_os) {
+                return Err(FileError::Busy);
+            }
+
+            let meta = self.partial_file_metadata(filename)?;
+            if meta.len() != offset {
+                return Err(FileError::OffsetMismatch {
+                    expected: offset,
+                    actual: meta.len(),
+                });
+            }
+
+            /* the partial file might have appeared after the directory was loaded */
+            if !names.contains(&filename_os) {
+                Self::check_file_count(&names, uc)?;
+            }
+            let slack = self.write_slack(total_size.saturating_sub(offset), uc.size_limit());
+            self.reserve_bytes(slack, uc.size_limit())?;
+            names.insert(filename_os.clone());
+            writers.insert(filename_os.clone());
+            (self.accounting.charge(&meta, filename.len()), slack)
+        };
+
+        let file = match OpenOptions::new()
+            .append(true)
+            .open(self.get_partial_file_name(filename))
+            .await
+        {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Error opening a partial file: {e}");
+                let mut names = self.filenames.lock().await;
+                self.writers.lock().await.remove(&filename_os);
+                self.report_bytes_released(slack);
+                /* removed in the meantime, nothing can be resumed anymore */
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    names.remove(&filename_os);
+                    self.report_bytes_released(initial_charge);
+                    return Err(FileError::NotFound);
+                }
+                return Err(FileError::Io(e));
+            }
+        };
+
+        Ok(DirectoryFileWriter::new(
+            self,
+            file,
+            uc.size_limit(),
+            filename,
+            Some(total_size),
+            uc.expiration_time(),
+        )
+        .limit_file_size(uc.file_size_limit())
+        .resume_from(offset)
+        .charged(initial_charge, slack))
+    }
+
+    /// Deletes an unfinished upload and frees the name for other uploads
+    pub async fn terminate_resumable_file(&self, filename: &str) -> Result<(), FileError> {
+        let filename_os = OsString::from(filename);
+
+        let mut names = self.filenames.lock().await;
+        if self.writers.lock().await.contains(&filename_os) {
+            return Err(FileError::Busy);
+        }
+
+        let meta = self.partial_file_metadata(filename)?;
+        if let Err(e) = async_std::fs::remove_file(self.get_partial_file_name(filename)).await {
+            error!("Error removing a terminated upload: {e}");
+            return Err(FileError::NotFound);
+        }
+        names.remove(&filename_os);
+
+        self.report_bytes_released(self.accounting.charge(&meta, filename.len()));
+        Ok(())
+    }
+
+    /// Deletes a finished file, its name can be used again and its bytes count as free
+    pub async fn delete_file(&self, filename: &str) -> Result<(), FileError> {
+        let filename_os = OsString::from(filename);
+
+        /* holding the lock prevents new uploads from claiming the name in the meantime */
+        let mut names = self.filenames.lock().await;
+        if self.writers.lock().await.contains(&filename_os) {
+            return Err(FileError::Busy);
+        }
+
+        let path = self.get_final_file_name(filename);
+        let meta = match std::fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_file() => meta,
+            _ => return Err(FileError::NotFound),
+        };
+        if let Err(e) = async_std::fs::remove_file(&path).await {
+            error!("Error deleting a file: {e}");
+            return Err(FileError::NotFound);
+        }
+        names.remove(&filename_os);
+
+        self.report_bytes_released(self.accounting.charge(&meta, filename.len()));
+        Ok(())
+    }
+
+    /// Renames a finished file, never overwrites an existing one
+    pub async fn rename_file(&self, from: &str, to: &str) -> Result<(), FileError> {
+        let from_os = OsString::from(from);
+        let to_os = OsString::from(to);
+
+        let mut names = self.filenames.lock().await;
+        if self.writers.lock().await.contains(&from_os) {
+            return Err(FileError::Busy);
+        }
+        if names.contains(&to_os) {
+            return Err(FileError::AlreadyExists);
+        }
+
+        let from_path = self.get_final_file_name(from);
+        let meta = match std::fs::symlink_metadata(&from_path) {
+            Ok(meta) if meta.is_file() => meta,
+            _ => return Err(FileError::NotFound),
+        };
+        if let Err(e) = rename_noreplace(&from_path, &self.get_final_file_name(to)) {
+            return Err(match e.kind() {
+                std::io::ErrorKind::AlreadyExists => FileError::AlreadyExists,
+                std::io::ErrorKind::NotFound => FileError::NotFound,
+                _ => {
+                    error!("Error renaming a file: {e}");
+                    FileError::Io(e)
+                }
+            });
+        }
+        names.remove(&from_os);
+        names.insert(to_os);
+
+        /* the accounted metadata overhead might depend on the name length */
+        self.adjust_charge(
+            self.accounting.charge(&meta, from.len()),
+            self.accounting.charge(&meta, to.len()),
+        );
+        Ok(())
+    }
+
+    /// Brings the filename set and the byte accounting in sync with the disk after the
+    /// directory was changed by someone else than the server. While an upload is in progress,
+    /// the bytes are recounted only after the last one finishes.
+    pub async fn reconcile(&self) -> anyhow::Result<()> {
+        let mut names = self.filenames.lock().await;
+        let writers = self.writers.lock().await;
+
+        /* the owner removed the directory, it's not brought back until somebody uploads again */
+        if !self.path.is_dir() {
+            self.removed.store(true, Ordering::Relaxed);
+            names.retain(|name| writers.contains(name));
+            self.report_bytes_released(self.get_total_bytes());
+            return Ok(());
+        }
+
+        /* names claimed by unfinished uploads stay claimed while their partial file exists */
+        let on_disk = Self::create_filename_set(&self.path)?;
+        names.retain(|name| on_disk.contains(name) || writers.contains(name));
+        names.extend(on_disk);
+
+        /* writers reserve bytes before they reach the disk */
+        if !writers.is_empty() {
+            self.recount_pending.store(true, Ordering::Relaxed);
+            return Ok(());
+        }
+        self.recount()
+    }
+
+    /// Replaces the byte accounting with what is on the disk, there must be no writers
+    fn recount(&self) -> anyhow::Result<()> {
+        let size = Self::calculate_existing_data_size(&self.path, self.accounting)?;
+        let previous = self.real_size.swap(size, Ordering::Relaxed);
+        self.quota.add(size);
+        self.quota.release(previous);
+        Ok(())
+    }
+
+    /// The directory was removed from the disk, it must not be used for new uploads
+    pub fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::Relaxed)
+    }
+
+    /// Whether the server itself is writing the file of the name right now
+    pub async fn is_being_written(&self, name: &OsStr) -> bool {
+        self.writers.lock().await.contains(name)
+    }
+
+    /// Deletes partial files of unfinished uploads which were not written to for longer than
+    /// `max_age`, so that their names can be used again. Returns names and sizes of the removed files.
+    pub async fn remove_stale_partial_files(
+        &self,
+        max_age: Duration,
+    ) -> anyhow::Result<Vec<(String, u64)>> {
+        let mut names = self.filenames.lock().await;
+        let writers = self.writers.lock().await;
+
+        let mut removed = vec![];
+        for entry in Self::staged_entries(&self.path)? {
+            let meta = match entry.metadata() {
+                Ok(meta) if meta.is_file() => meta,
+                _ => continue,
+            };
+            let name = entry.file_name();
+            let modified = match meta.modified() {
+                Ok(modified) => modified,
+                Err(e) => {
+                    error!(
+                        "Error reading the age of a partial file {:?}: {e}",
+                        entry.path()
+                    );
+                    continue;
+                }
+            };
+            if modified.elapsed().unwrap_or_default() < max_age || writers.contains(&name) {
+                continue;
+            }
+
+            /* one file which can't be removed must not keep the others around */
+            if let Err(e) = std::fs::remove_file(entry.path()) {
+                error!(
+                    "Error removing a stale partial file {:?}: {e}",
+                    entry.path()
+                );
+                continue;
+            }
+            self.report_bytes_released(self.accounting.charge(&meta, name.len()));
+            /* the name stays claimed when a finished file of the same name appeared in the meantime */
+            if !self.path.join(&name).exists() {
+                names.remove(&name);
+            }
+            removed.push((name.to_string_lossy().into_owned(), meta.len()));
+        }
+        Ok(removed)
+    }
+
+    /// Charges a new empty partial file together with `slack` for the writes into it. A partial
+    /// file left by a previous attempt is already charged, it's replaced, because it's going to be truncated.
+    fn charge_new_partial_file(
+        &self,
+        filename: &str,
+        slack: u64,
+        limit: u64,
+    ) -> Result<(), FileError> {
+        let old = match std::fs::metadata(self.get_partial_file_name(filename)) {
+            Ok(meta) => self.accounting.charge(&meta, filename.len()),
+            Err(_) => 0,
+        };
+        let new = self.accounting.empty_file_charge(filename.len()) + slack;
+        if new > old {
+            self.reserve_bytes(new - old, limit)?;
         }
+        self.report_bytes_released(old.saturating_sub(new));
+        Ok(())
     }
 
-    fn assert_path_existence(path: &Path) {
-        assert!(path.exists(), "Directory does not exist");
-        assert!(path.is_dir(), "Path is not a directory");
+    /// Releases what was charged for a partial file which couldn't be opened. A partial file
+    /// of the same name left by a previous attempt was replaced by the charge, it's removed.
+    fn give_up_partial_file(&self, filename: &str, charge: u64) {
+        _ = std::fs::remove_file(self.get_partial_file_name(filename));
+        self.report_bytes_released(charge);
     }
 
-    fn create_filename_set(path: &Path) -> anyhow::Result<HashSet<OsString>> {
-        Ok(read_dir(path)?.map(|e| e.unwrap().file_name()).collect())
+    /// Deletes the partial file of a failed upload and releases all its bytes
+    async fn discard_partial_file(&self, filename: &str) {
+        let path = self.get_partial_file_name(filename);
+        let charge = match std::fs::metadata(&path) {
+            Ok(meta) => self.accounting.charge(&meta, filename.len()),
+            Err(_) => return,
+        };
+        if let Err(e) = async_std::fs::remove_file(&path).await {
+            error!("Error removing a failed upload: {e}");
+            return;
+        }
+        self.report_bytes_released(charge);
     }
 
-    fn calculate_existing_data_size(dir: &Path) -> anyhow::Result<u64> {
-        let mut size = 0u64;
-        for dir in read_dir(dir)?.flatten() {
-            size += dir.metadata().unwrap().size(); // file content
-            size += dir.file_name().len() as u64; // length of the file name
-            size += 4096; // constant overhead to account for metadata space of empty files
+    /// Extra bytes charged while `size` bytes are being written. The allocated space is known
+    /// only after the data are written. Besides the last block filled only partially, the
+    /// filesystem might need blocks mapping the data blocks (one per `block_size / 4` data blocks
+    /// with indirect blocks). The slack never takes more than what's left after the data, what
+    /// doesn't fit into it is checked against the limits when the writer is finalized.
+    fn write_slack(&self, size: u64, limit: u64) -> u64 {
+        match self.accounting {
+            Accounting::Apparent => 0,
+            Accounting::Allocated => {
+                let data_blocks = size.div_ceil(self.block_size);
+                let mapping_blocks = data_blocks.div_ceil(self.block_size / 4);
+                let left = limit.saturating_sub(self.get_total_bytes().saturating_add(size));
+                ((1 + mapping_blocks) * self.block_size).min(left)
+            }
         }
-
-        Ok(size)
     }
 
-    pub async fn create_file_writer<'a>(
-        &'a self,
-        uc: &UploadCapability,
-        filename: &'a str,
-        expected_size: Option<u64>,
-    ) -> Result<DirectoryFileWriter<'a>, String> {
-        Self::assert_path_existence(&self.path);
-        let partial_name = self.get_partial_file_name(filename);
-
-        let filename_os = OsString::from(filename);
+    /// Replaces a charge computed earlier with the current one
+    fn adjust_charge(&self, old: u64, new: u64) {
+        if new > old {
+            self.report_bytes_written((new - old) as usize);
+        } else {
+            self.report_bytes_released(old - new);
+        }
+    }
 
-        /* lock the filename we are working on */
-        {
-            /* check for finished file name collision & claim it if it's free */
-            let mut names = self.filenames.lock().await;
-            if names.contains(&filename_os) {
-                return Err("file already exists".to_owned());
-            }
-            names.insert(filename_os);
+    /// Replaces a charge computed earlier with the current one, fails when the growth doesn't
+    /// fit into the limits. The bytes are charged even then, because they are used on the disk
+    /// until the caller removes them.
+    fn recharge(&self, old: u64, new: u64, limit: u64) -> Result<(), FileError> {
+        if new <= old {
+            self.report_bytes_released(old - new);
+            return Ok(());
+        }
+        let res = self.reserve_bytes(new - old, limit);
+        if res.is_err() {
+            self.report_bytes_written((new - old) as usize);
         }
+        res
+    }
 
-        /* there is still a possibility that a partial file exists, however we can
-        be certain that we are not writing into it at the moment, so lets just ignore it
-        and rewrite it */
-
-        /* create partial file writer */
-        let file = OpenOptions::new()
-            .create(true) // this allows rewrites
-            .truncate(true)
-            .write(true)
-            .open(partial_name)
-            .await
-            .expect("creating a new file should never fail here");
+    fn check_file_count(names: &HashSet<OsString>, uc: &UploadCapability) -> Result<(), FileError> {
+        match uc.file_count_limit() {
+            Some(limit) if names.len() as u64 >= limit => Err(FileError::TooManyFiles { limit }),
+            _ => Ok(()),
+        }
+    }
 
-        /* report file constant bytes (same as in calculate_existing_data_size()) */
-        self.report_bytes_written(4096);
-        self.report_bytes_written(filename.len());
+    /// Charges the bytes only when the total stays within the limit and the server-wide limits
+    fn reserve_bytes(&self, bytes: u64, limit: u64) -> Result<(), FileError> {
+        if !self.quota.reserve(bytes, &self.path) {
+            return Err(FileError::InsufficientStorage);
+        }
 
-        /* create file writer object */
-        Ok(DirectoryFileWriter::new(
-            self,
-            &self.real_size,
-            file,
-            uc.size_limit(),
-            filename,
-            expected_size,
-            uc.expiration_time(),
-        ))
+        /* CAS loop
+        - always Relaxed ordering, because we are working with just a single variable
+          and there is no other operation that could be reorderd incorrectly */
+        let mut current_value = self.real_size.load(Ordering::Relaxed);
+        loop {
+            /* every time we loop, check if the bytes fit into the limit */
+            if current_value + bytes > limit {
+                self.quota.release(bytes);
+                return Err(FileError::DataLimitExceeded);
+            }
+            /* if they do, try to allocate */
+            match self.real_size.compare_exchange_weak(
+                current_value,
+                current_value + bytes,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(real) => {
+                    /* allocation unsucessfull, try again */
+                    current_value = real;
+                }
+            }
+        }
     }
 
     fn report_bytes_written(&self, bytes: usize) {
         self.real_size.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.quota.add(bytes as u64);
+    }
+
+    fn report_bytes_released(&self, bytes: u64) {
+        let previous = self
+            .real_size
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                Some(v.saturating_sub(bytes))
+            })
+            .unwrap_or_default();
+        self.quota.release(bytes.min(previous));
     }
 
     pub fn get_total_bytes(&self) -> u64 {
         self.real_size.load(Ordering::Relaxed)
     }
 
+    /// Size of the largest file which can still be uploaded, files take at least the slack
     pub fn get_remaining_bytes(&self, uc: &UploadCapability) -> u64 {
         u64::saturating_sub(uc.size_limit(), self.get_total_bytes())
+            .min(self.get_remaining_storage())
+            .saturating_sub(self.write_slack(0, u64::MAX))
+    }
+
+    /// Bytes the server can still store regardless of the link's data limit
+    pub fn get_remaining_storage(&self) -> u64 {
+        self.quota.remaining_bytes(&self.path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     fn get_partial_file_name(&self, name: &str) -> PathBuf {
-        let name = format!("{name}$.partial");
-        Path::join(&self.path, name)
+        Path::join(&Self::staging_path(&self.path), name)
     }
 
-    fn get_final_file_name(&self, name: &str) -> PathBuf {
+    pub fn get_final_file_name(&self, name: &str) -> PathBuf {
         Path::join(&self.path, name)
     }
 
@@ -236,6 +1309,74 @@ impl Directory {
         let names = self.filenames.lock().await;
         names.clone().into_iter().collect()
     }
+
+    /// Lists files which were completely uploaded together with their sizes, sorted by name
+    pub fn list_finished_files(&self) -> anyhow::Result<Vec<(OsString, u64)>> {
+        Self::list_finished_files_in(&self.path)
+    }
+
+    /// Same as `list_finished_files`, but works without loading the directory
+    pub fn list_finished_files_in(path: &Path) -> anyhow::Result<Vec<(OsString, u64)>> {
+        let mut files: Vec<(OsString, u64)> = read_dir(path)?
+            .flatten()
+            .filter_map(|e| match e.metadata() {
+                Ok(meta) if meta.is_file() => Some((e.file_name(), meta.len())),
+                _ => None,
+            })
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+
+    /// Summarizes the directory without loading it
+    pub fn summarize(
+        name: &str,
+        path: &Path,
+        accounting: Accounting,
+    ) -> anyhow::Result<DirectorySummary> {
+        let mut summary = DirectorySummary {
+            name: name.to_owned(),
+            files: 0,
+            total_bytes: Self::calculate_existing_data_size(path, accounting)?,
+            last_upload: None,
+            partial_uploads: Vec::new(),
+        };
+
+        for entry in read_dir(path)?.flatten() {
+            let meta = match entry.metadata() {
+                Ok(meta) if meta.is_file() => meta,
+                _ => continue,
+            };
+            summary.files += 1;
+            summary.last_upload = summary.last_upload.max(Some(meta.modified()?));
+        }
+        for entry in Self::staged_entries(path)? {
+            let meta = match entry.metadata() {
+                Ok(meta) if meta.is_file() => meta,
+                _ => continue,
+            };
+            summary.partial_uploads.push(PartialUpload {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                size: meta.len(),
+                modified: meta.modified()?,
+            });
+        }
+        summary.partial_uploads.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(summary)
+    }
+
+    /// Opens a completely uploaded file for reading, returns it together with its size
+    pub async fn open_finished_file(&self, name: &str) -> Result<(File, u64), FileError> {
+        let path = self.get_final_file_name(name);
+        match std::fs::metadata(&path) {
+            Ok(meta) if meta.is_file() => {
+                let file = File::open(path).await.map_err(|_| FileError::NotFound)?;
+                Ok((file, meta.len()))
+            }
+            _ => Err(FileError::NotFound),
+        }
+    }
 }
 
 pub struct DirectoryFileWriter<'a> {
@@ -246,22 +1387,36 @@ pub struct DirectoryFileWriter<'a> {
     /* internal state variables */
     errored: bool,
     finalized: bool,
-    write_in_progress: bool,
+    out_of_storage: bool,
+    /// the charge was replaced with the space really used by the file
+    settled: bool,
+    /// the space really used by the file didn't fit into the limits
+    over_limit: bool,
+    /// bytes charged for a write which did not complete yet
+    reserved: Option<u64>,
 
     /* helper values for enforcing the size limit */
-    total_size: &'a AtomicU64,
     expected_size: Option<u64>,
     bytes_written: u64,
+    stream_complete: bool,
+
+    /* resumable uploads keep their partial file when not completed */
+    resumable: bool,
+    offset: u64,
+
+    /* bytes charged for the file before the first write and for the duration of the writes */
+    initial_charge: u64,
+    slack: u64,
 
     /* limits */
     max_dir_size: u64,
+    max_file_size: Option<u64>,
     expiration_time: SystemTime,
 }
 
 impl<'a> DirectoryFileWriter<'a> {
     pub fn new(
         dir: &'a Directory,
-        total_size: &'a AtomicU64,
         file: File,
         max_dir_size: u64,
         filename: &'a str,
@@ -270,30 +1425,155 @@ impl<'a> DirectoryFileWriter<'a> {
     ) -> Self {
         Self {
             dir,
-            total_size,
             file,
             max_dir_size,
+            max_file_size: None,
             errored: false,
-            write_in_progress: false,
+            out_of_storage: false,
+            settled: false,
+            over_limit: false,
+            reserved: None,
             filename,
             expected_size,
             finalized: false,
             bytes_written: 0,
+            stream_complete: false,
             expiration_time,
+            resumable: false,
+            offset: 0,
+            initial_charge: 0,
+            slack: 0,
         }
     }
 
+    pub fn limit_file_size(mut self, max_file_size: Option<u64>) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Turns the writer into one appending to an unfinished upload which already
+    /// contains `offset` bytes. The expected size is then the size of the whole file.
+    pub fn resume_from(mut self, offset: u64) -> Self {
+        self.resumable = true;
+        self.offset = offset;
+        self
+    }
+
+    /// Sets what was charged for the file before any data were written, it's replaced by what
+    /// the file really uses when the writer is finalized
+    pub fn charged(mut self, initial_charge: u64, slack: u64) -> Self {
+        self.initial_charge = initial_charge;
+        self.slack = slack;
+        self
+    }
+
+    /// Marks that the whole body was received and properly terminated. This is used for
+    /// chunked uploads without the Content-Length header, where the HTTP layer reports
+    /// a truncated stream as an error, but a clean end of the stream normally.
+    pub fn mark_stream_complete(&mut self) {
+        self.stream_complete = true;
+    }
+
+    /// The upload was stopped, because the server is running out of storage space
+    pub fn is_out_of_storage(&self) -> bool {
+        self.out_of_storage
+    }
+
     pub fn get_bytes_really_written(&self) -> u64 {
         self.bytes_written
     }
 
+    /// Size of the file including data stored before this writer was created
+    pub fn get_current_offset(&self) -> u64 {
+        self.offset + self.bytes_written
+    }
+
+    /// Returns the bytes charged for a write which is not going to happen
+    fn release_reservation(&mut self) {
+        if let Some(reserved) = self.reserved.take() {
+            self.dir.report_bytes_released(reserved);
+        }
+    }
+
+    /// Replaces what was charged for the written data with the space the file really uses, that
+    /// is known only after the data are stored. When it doesn't fit into the limits, the data
+    /// written by this writer are given up. Called by [`DirectoryFileWriter::finalize`], call it
+    /// earlier to learn the final offset and whether the storage ran out.
+    pub async fn settle_charge(&mut self) -> Result<(), FileError> {
+        if self.settled {
+            return Ok(());
+        }
+        self.settled = true;
+        self.release_reservation();
+
+        /* make sure everything we've accepted is really stored, resumed uploads continue from the file size */
+        if let Err(e) = async_std::io::WriteExt::flush(&mut self.file).await {
+            error!("Error flushing file \"{}\": {e}", self.filename);
+            self.errored = true;
+        }
+
+        let charged = self.initial_charge + self.bytes_written + self.slack;
+        let used = match self.file.metadata().await {
+            Ok(meta) => self.dir.accounting.charge(&meta, self.filename.len()),
+            Err(e) => {
+                error!("Error reading metadata of file \"{}\": {e}", self.filename);
+                return Ok(());
+            }
+        };
+        let Err(err) = self.dir.recharge(charged, used, self.max_dir_size) else {
+            return Ok(());
+        };
+
+        warn!(
+            "upload of \"{}\" uses more space than expected and doesn't fit into the limits: {err}",
+            self.filename
+        );
+        self.errored = true;
+        self.over_limit = true;
+        self.out_of_storage = matches!(err, FileError::InsufficientStorage);
+        if !self.resumable || self.bytes_written == 0 {
+            /* the file is going to be discarded */
+            return Err(err);
+        }
+
+        /* the data stored before are kept, so that the upload can be resumed from there */
+        if let Err(e) = self.file.set_len(self.offset).await {
+            error!("Error truncating file \"{}\": {e}", self.filename);
+            return Err(err);
+        }
+        self.bytes_written = 0;
+        match self.file.metadata().await {
+            Ok(meta) => self
+                .dir
+                .adjust_charge(used, self.dir.accounting.charge(&meta, self.filename.len())),
+            Err(e) => error!("Error reading metadata of file \"{}\": {e}", self.filename),
+        }
+        Err(err)
+    }
+
     pub async fn finalize(mut self) -> Vec<String> {
         self.finalized = true;
+        _ = self.settle_charge().await;
         let mut msgs = vec![];
+        if self.over_limit {
+            msgs.push(
+                "the stored data take more space on the disk than the limits allow".to_owned(),
+            );
+        }
 
         /* we won't be notified, if the stream ends in the middle, it will just end normally on our side,
-        therefore, to check for completion, we use the Content-Length header */
-        if let Some(expected) = self.expected_size {
+        therefore, to check for completion, we use the Content-Length header. Chunked streams
+        are the exception, they have an explicit terminating chunk. */
+        if self.resumable {
+            let current = self.get_current_offset();
+            let expected = self.expected_size.unwrap_or(current);
+            if current != expected {
+                msgs.push(format!(
+                    "upload paused at {current} of {expected} bytes, it can be resumed"
+                ));
+                self.errored = true;
+            }
+        } else if let Some(expected) = self.expected_size {
             if expected != self.bytes_written {
                 warn!(
                     "upload of \"{}\" not completed: expected={expected} real={}",
@@ -302,29 +1582,46 @@ impl<'a> DirectoryFileWriter<'a> {
                 msgs.push(format!("upload not completed, we expected {expected} bytes due to the Content-Length header, but received only {}", self.bytes_written));
                 self.errored = true; // we consider this state an error and will prevent renaming
             }
-        } else {
-            warn!("upload of \"{}\" did not contain Content-Length header, leaving it with partial name", self.filename);
-            msgs.push("your request did not contain the Content-Length header, leaving it with partial name".to_owned());
+        } else if !self.stream_complete {
+            warn!("upload of \"{}\" did not contain Content-Length header nor a complete chunked body, discarding it", self.filename);
+            msgs.push("your request did not contain the Content-Length header nor a properly terminated chunked body, the upload was discarded".to_owned());
             self.errored = true;
         };
 
         /* warn about data limit exhaustion */
-        if self.max_dir_size <= self.total_size.load(Ordering::Relaxed) {
+        if self.max_dir_size <= self.dir.get_total_bytes() {
             msgs.push("data limit reached while uploading".to_owned());
         }
 
+        let name = OsString::from(self.filename);
         if !self.errored {
             /* if no errors occured, lets rename the file to its final non-partial name */
             if let Err(e) = self.dir.mark_upload_final(self.filename).await {
                 error!("Error renaming file after an upload: {e}");
                 msgs.push("error renaming file after a complete upload, you can retry by uploading it again".to_owned());
+                self.errored = true;
             }
-        } else {
-            /* by removing the file from the hash set, we allow its reuploads */
+        }
+        if self.errored && !self.resumable {
+            /* nobody can continue the upload, so its data are deleted and by removing
+            the file from the hash set, we allow its reuploads */
             let mut lock = self.dir.filenames.lock().await;
-            let name = OsString::from(self.filename);
+            self.dir.discard_partial_file(self.filename).await;
             _ = lock.remove(&name);
         }
+        /* resumable uploads keep their name claimed until completed or terminated */
+
+        let _names = self.dir.filenames.lock().await;
+        let mut writers = self.dir.writers.lock().await;
+        writers.remove(&name);
+        if writers.is_empty() && self.dir.recount_pending.swap(false, Ordering::Relaxed) {
+            if let Err(e) = self.dir.recount() {
+                error!(
+                    "Error recounting the bytes of directory {:?}: {e}",
+                    self.dir.path
+                );
+            }
+        }
 
         msgs
     }
@@ -338,65 +1635,63 @@ impl<'a> async_std::io::Write for DirectoryFileWriter<'a> {
     ) -> std::task::Poll<std::io::Result<usize>> {
         let this = self.get_mut();
 
-        /* reserve the bytes (CAS loop)
-        - always Relaxed ordering, because we are working with just a single variable
-          and there is no other operation that could be reorderd incorrectly
-        - do this only once, before the actual write starts */
-        if !this.write_in_progress {
-            let mut current_value = this.total_size.load(Ordering::Relaxed);
-            let addition = buf.len() as u64;
-            loop {
-                /* every time we loop, check if the bytes fit into the limit */
-                if current_value + addition <= this.max_dir_size {
-                    /* if they do, try to allocate */
-                    match this.total_size.compare_exchange_weak(
-                        current_value,
-                        current_value + addition,
-                        Ordering::Relaxed,
-                        Ordering::Relaxed,
-                    ) {
-                        Ok(_) => {
-                            /* allocation sucessfull, continue with the actual write */
-                            break;
-                        }
-                        Err(real) => {
-                            /* allocation unsucessfull, try again */
-                            current_value = real;
-                        }
-                    }
-                } else {
-                    /* if the buffer does not fit, return error */
-                    this.errored = true;
-                    return std::task::Poll::Ready(Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "data limit exceeded",
-                    )));
-                }
+        /* never accept more data than announced */
+        if let Some(expected) = this.expected_size {
+            if this.get_current_offset() + buf.len() as u64 > expected {
+                this.errored = true;
+                return std::task::Poll::Ready(Err(std::io::Error::other(
+                    "received more data than announced",
+                )));
+            }
+        }
+
+        /* enforce the per-file limit */
+        if let Some(limit) = this.max_file_size {
+            if this.get_current_offset() + buf.len() as u64 > limit {
+                this.errored = true;
+                return std::task::Poll::Ready(Err(std::io::Error::other(
+                    "file size limit exceeded",
+                )));
             }
         }
 
         /* check if it's not too late */
         if SystemTime::now() > this.expiration_time {
             this.errored = true;
-            return std::task::Poll::Ready(Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "time limit expired",
-            )));
+            this.release_reservation();
+            return std::task::Poll::Ready(Err(std::io::Error::other("time limit expired")));
+        }
+
+        /* reserve the bytes, do this only once, before the actual write starts */
+        if this.reserved.is_none() {
+            if let Err(err) = this.dir.reserve_bytes(buf.len() as u64, this.max_dir_size) {
+                /* if the buffer does not fit, return error */
+                this.errored = true;
+                this.out_of_storage = matches!(err, FileError::InsufficientStorage);
+                return std::task::Poll::Ready(Err(std::io::Error::other(err.to_string())));
+            }
+            this.reserved = Some(buf.len() as u64);
         }
 
         /* write the bytes */
-        this.write_in_progress = true;
         let res = async_std::io::Write::poll_write(Pin::new(&mut this.file), cx, buf);
 
-        if let std::task::Poll::Ready(Ok(size)) = &res {
-            /* this function does not necessarily write the whole buffer, so deallocate unused bytes */
-            this.total_size
-                .fetch_sub((buf.len() - size) as u64, Ordering::Relaxed);
+        match &res {
+            std::task::Poll::Ready(Ok(size)) => {
+                /* this function does not necessarily write the whole buffer, so deallocate unused bytes */
+                if let Some(reserved) = this.reserved.take() {
+                    this.dir.report_bytes_released(reserved - *size as u64);
+                }
 
-            /* update internal state */
-            this.write_in_progress = false;
-            this.bytes_written += *size as u64;
-        };
+                /* update internal state */
+                this.bytes_written += *size as u64;
+            }
+            std::task::Poll::Ready(Err(_)) => {
+                this.errored = true;
+                this.release_reservation();
+            }
+            std::task::Poll::Pending => {}
+        }
 
         res
     }
@@ -428,31 +1723,617 @@ impl<'a> Drop for DirectoryFileWriter<'a> {
     }
 }
 
+impl Drop for Directory {
+    fn drop(&mut self) {
+        self.quota.unload(&self.path, self.get_total_bytes());
+    }
+}
+
 #[derive(Clone)]
 pub struct DirectoryRegistry {
+    /// data root, the directories are its subdirectories
+    root: PathBuf,
     real_sizes: Arc<Mutex<HashMap<String, Weak<Directory>>>>,
+    watcher: Option<DirectoryWatcher>,
+    accounting: Accounting,
+    quota: Arc<StorageQuota>,
 }
 
 impl DirectoryRegistry {
-    pub fn new() -> Self {
+    pub fn new(root: PathBuf, accounting: Accounting, quota: StorageQuota) -> Self {
+        let watcher = match DirectoryWatcher::start() {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                warn!("Changes made to the directories by hand won't be detected, watching them failed: {e}");
+                None
+            }
+        };
+        Self::with_watcher(root, accounting, quota, watcher)
+    }
+
+    /// Same as `new`, but the changes are detected by an already running watcher
+    pub fn with_watcher(
+        root: PathBuf,
+        accounting: Accounting,
+        quota: StorageQuota,
+        watcher: Option<DirectoryWatcher>,
+    ) -> Self {
         Self {
+            root,
             real_sizes: Arc::new(Mutex::new(HashMap::new())),
+            watcher,
+            accounting,
+            quota: Arc::new(quota),
         }
     }
 
+    pub fn accounting(&self) -> Accounting {
+        self.accounting
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Path of the directory of the name, it doesn't have to exist
+    pub fn path(&self, directory_name: &str) -> PathBuf {
+        self.root.join(directory_name)
+    }
+
     pub async fn get(&self, directory_name: &str) -> anyhow::Result<Arc<Directory>> {
         let mut lock = self.real_sizes.lock().await;
 
         let res = lock.get(directory_name);
         if let Some(wk) = res {
-            if let Some(rc) = wk.upgrade() {
+            if let Some(rc) = wk.upgrade().filter(|rc| !rc.is_removed()) {
                 return Ok(rc);
             }
         }
 
-        let dir = Directory::new(PathBuf::from(directory_name))?;
+        let dir = Directory::new(
+            self.path(directory_name),
+            self.accounting,
+            self.quota.clone(),
+        )?;
         let res = Arc::new(dir);
         lock.insert(directory_name.to_owned(), Arc::<Directory>::downgrade(&res));
+        if let Some(watcher) = &self.watcher {
+            watcher.watch(&res);
+        }
         Ok(res)
     }
+
+    /// Bytes used by the directory. When it's in use, the in-memory accounting including
+    /// uploads in progress is used, otherwise it's calculated from the disk.
+    pub async fn get_usage(&self, directory_name: &str) -> anyhow::Result<u64> {
+        let loaded = self
+            .real_sizes
+            .lock()
+            .await
+            .get(directory_name)
+            .and_then(Weak::upgrade);
+        match loaded {
+            Some(dir) => Ok(dir.get_total_bytes()),
+            None => {
+                Directory::calculate_existing_data_size(&self.path(directory_name), self.accounting)
+            }
+        }
+    }
+
+    /// Counts the directories which are not loaded from the disk for the server-wide quota
+    pub fn refresh_storage_usage(&self) -> anyhow::Result<()> {
+        let mut sizes = HashMap::new();
+        for name in self.list_directory_names()? {
+            let path = self.path(&name);
+            let size = Directory::calculate_existing_data_size(&path, self.accounting)?;
+            sizes.insert(path, size);
+        }
+        self.quota.refresh_unloaded(sizes);
+        Ok(())
+    }
+
+    /// Names of all directories in the data root, hidden ones are reserved for the server
+    pub fn list_directory_names(&self) -> anyhow::Result<Vec<String>> {
+        let mut names: Vec<String> = read_dir(&self.root)?
+            .flatten()
+            .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+            .filter_map(|e| e.file_name().into_string().ok())
+            .filter(|name| validate_dir_name(name).is_ok())
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+}
+
+#[test]
+fn test_file_type_allowlist() {
+    let cap = UploadCapability::new("dir".to_owned(), 100, 100)
+        .with_accepted_file_types(Some("image/*, .pdf".to_owned()));
+    assert!(cap.validate().is_ok());
+    assert!(cap.allows_file_type("photo.JPG", Some("image/jpeg")));
+    assert!(cap.allows_file_type("document.pdf", Some("application/pdf")));
+    assert!(cap.allows_file_type("document.pdf", None));
+    assert!(cap.allows_file_name("photo.jpg"));
+    assert!(!cap.allows_file_name("notes.txt"));
+
+    /* the content must be what the name says */
+    assert!(!cap.allows_file_type("document.pdf", Some("application/zip")));
+    assert!(!cap.allows_file_type("archive.jpg", Some("application/zip")));
+    assert!(!cap.allows_file_type("photo.png", Some("image/jpeg")));
+
+    /* MIME entries need recognized content */
+    assert!(!cap.allows_file_type("photo.jpg", None));
+    assert!(!cap.allows_file_type("notes.txt", None));
+    assert!(!cap.allows_file_type("noextension", None));
+    assert!(!cap.allows_file_type("noextension", Some("image/png")));
+}
+
+#[test]
+fn test_file_types_sharing_a_container() {
+    let cap = UploadCapability::new("dir".to_owned(), 100, 100).with_accepted_file_types(Some(
+        ".xls, .pptx, application/vnd.oasis.opendocument.text".to_owned(),
+    ));
+
+    /* what the content sniffing really reports for these */
+    assert!(cap.allows_file_type("table.xls", Some("application/msword")));
+    assert!(cap.allows_file_type(
+        "slides.pptx",
+        Some(
+            "application/application/vnd.openxmlformats-officedocument.presentationml.presentation"
+        )
+    ));
+    assert!(cap.allows_file_type("letter.odt", Some("application/zip")));
+
+    /* the container doesn't match formats built on another one */
+    assert!(!cap.allows_file_type("table.xls", Some("application/zip")));
+    assert!(!cap.allows_file_type("slides.pptx", Some("application/msword")));
+    assert!(!cap.allows_file_type("letter.odt", Some("image/png")));
+}
+
+#[test]
+fn test_capability_kinds_are_not_interchangeable() {
+    let upload = UploadCapability::new("dir".to_owned(), 100, 100).to_string();
+    let download = DownloadCapability::new("dir".to_owned(), 100).to_string();
+    assert!(DownloadCapability::from_str(&upload).is_err());
+    assert!(UploadCapability::from_str(&download).is_err());
+    assert!(DownloadCapability::from_str(&download).is_ok());
+
+    let session = SessionCapability::new(100).to_string();
+    assert!(SessionCapability::from_str(&session).is_ok());
+    for other in [&upload, &download] {
+        assert!(SessionCapability::from_str(other).is_err());
+    }
+    assert!(ApiKeyCapability::from_str(&session).is_err());
+    assert!(DownloadCapability::from_str(&session).is_err());
+    assert!(UploadCapability::from_str(&session).is_err());
+
+    let api_key = ApiKeyCapability::new("dir".to_owned(), 100).to_string();
+    assert!(SessionCapability::from_str(&api_key).is_err());
+    assert!(ApiKeyCapability::from_str(&upload).is_err());
+    assert!(ApiKeyCapability::from_str(&download).is_err());
+    assert!(UploadCapability::from_str(&api_key).is_err());
+    assert!(DownloadCapability::from_str(&api_key).is_err());
+    assert!(ApiKeyCapability::from_str(&api_key).is_ok());
+}
+
+/// Empty directory for a test, it's removed again when dropped
+#[cfg(test)]
+pub struct TestDir(PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("gimmedat-{name}-test-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+
+    fn load(&self) -> Directory {
+        Directory::new(self.0.clone(), Accounting::Apparent, Arc::default()).unwrap()
+    }
+
+    /// Capability with enough space for every test upload
+    fn capability() -> UploadCapability {
+        UploadCapability::new("dir".to_owned(), 1 << 20, 100)
+    }
+}
+
+/// The bytes charged to the directory are what its files take up on the disk
+#[cfg(test)]
+fn assert_accounting_matches_disk(dir: &Directory) {
+    assert_eq!(
+        dir.get_total_bytes(),
+        Directory::calculate_existing_data_size(dir.path(), Accounting::Apparent).unwrap()
+    );
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn test_delete_and_rename_update_accounting() {
+    let path = TestDir::new("owner");
+    std::fs::write(path.join("a.txt"), "0123456789").unwrap();
+    std::fs::write(path.join("b.txt"), "").unwrap();
+
+    async_std::task::block_on(async {
+        let dir = path.load();
+        let initial = dir.get_total_bytes();
+
+        assert!(matches!(
+            dir.rename_file("a.txt", "b.txt").await,
+            Err(FileError::AlreadyExists)
+        ));
+        /* the existing file is kept even when the server doesn't know about it yet */
+        std::fs::write(path.join("c.txt"), "").unwrap();
+        assert!(matches!(
+            dir.rename_file("a.txt", "c.txt").await,
+            Err(FileError::AlreadyExists)
+        ));
+        std::fs::remove_file(path.join("c.txt")).unwrap();
+
+        dir.rename_file("a.txt", "longer.txt").await.unwrap();
+        assert_eq!(dir.get_total_bytes(), initial + 5);
+        assert!(!path.join("a.txt").exists());
+        assert!(matches!(
+            dir.delete_file("a.txt").await,
+            Err(FileError::NotFound)
+        ));
+
+        dir.delete_file("longer.txt").await.unwrap();
+        dir.delete_file("b.txt").await.unwrap();
+        assert_eq!(dir.get_total_bytes(), 0);
+        assert!(dir.list_files().await.is_empty());
+    });
+}
+
+#[test]
+fn test_recharge_checks_the_limit() {
+    let path = TestDir::new("recharge");
+    let dir = path.load();
+
+    dir.report_bytes_written(100);
+    dir.recharge(100, 40, 150).unwrap();
+    assert_eq!(dir.get_total_bytes(), 40);
+    dir.recharge(40, 150, 150).unwrap();
+    assert_eq!(dir.get_total_bytes(), 150);
+
+    /* bytes which are already on the disk are charged even when they don't fit */
+    assert!(matches!(
+        dir.recharge(0, 100, 150),
+        Err(FileError::DataLimitExceeded)
+    ));
+    assert_eq!(dir.get_total_bytes(), 250);
+}
+
+#[test]
+fn test_reconcile_with_external_changes() {
+    let path = TestDir::new("reconcile");
+    std::fs::write(path.join("a.txt"), "0123456789").unwrap();
+
+    async_std::task::block_on(async {
+        let dir = path.load();
+        let uc = TestDir::capability();
+        dir.create_resumable_file(&uc, "paused.bin").await.unwrap();
+
+        std::fs::remove_file(path.join("a.txt")).unwrap();
+        std::fs::write(path.join("b.txt"), "01234").unwrap();
+        dir.reconcile().await.unwrap();
+
+        let mut names = dir.list_files().await;
+        names.sort();
+        assert_eq!(names, ["b.txt", "paused.bin"]);
+        assert_accounting_matches_disk(&dir);
+
+        /* the deleted file can be uploaded again, changes during the upload are counted after it */
+        let mut writer = dir.create_file_writer(&uc, "a.txt", Some(0)).await.unwrap();
+        std::fs::write(path.join("c.txt"), "0123456789").unwrap();
+        dir.reconcile().await.unwrap();
+        assert!(dir.list_files().await.contains(&"c.txt".into()));
+        writer.mark_stream_complete();
+        assert!(writer.finalize().await.is_empty());
+        assert_accounting_matches_disk(&dir);
+
+        /* a removed directory is forgotten instead of being created again */
+        std::fs::remove_dir_all(&*path).unwrap();
+        dir.reconcile().await.unwrap();
+        assert!(dir.is_removed());
+        assert!(!path.exists());
+        assert_eq!(dir.get_total_bytes(), 0);
+    });
+}
+
+#[test]
+fn test_partial_files_are_staged() {
+    let path = TestDir::new("staging");
+    std::fs::write(path.join("old.bin$.partial"), "0123").unwrap();
+
+    async_std::task::block_on(async {
+        let dir = path.load();
+        let uc = TestDir::capability();
+
+        /* partial files left by older versions can still be resumed */
+        assert_eq!(dir.list_files().await, ["old.bin"]);
+        assert_eq!(dir.get_resumable_offset("old.bin").await.unwrap(), 4);
+
+        /* names of partial files don't collide with finished ones */
+        dir.create_resumable_file(&uc, "photo.jpg").await.unwrap();
+        let mut writer = dir
+            .create_file_writer(&uc, "photo.jpg$.partial", Some(0))
+            .await
+            .unwrap();
+        writer.mark_stream_complete();
+        assert!(writer.finalize().await.is_empty());
+
+        let finished = dir.list_finished_files().unwrap();
+        assert_eq!(finished, [(OsString::from("photo.jpg$.partial"), 0)]);
+        assert_accounting_matches_disk(&dir);
+    });
+
+    /* the partial files are moved only once, finished files are left alone afterwards */
+    let dir = path.load();
+    assert_eq!(
+        dir.list_finished_files().unwrap(),
+        [(OsString::from("photo.jpg$.partial"), 0)]
+    );
+    drop(dir);
+    assert!(validate_file_name(STAGING_DIR).is_err());
+    assert!(validate_file_name("photo.jpg$.partial").is_err());
+
+    /* a removed staging directory is created again, when that's not possible the upload fails cleanly */
+    async_std::task::block_on(async {
+        let dir = path.load();
+        let uc = TestDir::capability();
+        let staging = Directory::staging_path(&path);
+        std::fs::remove_dir_all(&staging).unwrap();
+        dir.create_resumable_file(&uc, "recreated.bin")
+            .await
+            .unwrap();
+
+        std::fs::remove_dir_all(&staging).unwrap();
+        std::fs::write(&staging, "").unwrap();
+        let total = dir.get_total_bytes();
+        assert!(matches!(
+            dir.create_file_writer(&uc, "failed.bin", None).await,
+            Err(FileError::Io(_))
+        ));
+        assert!(matches!(
+            dir.create_resumable_file(&uc, "failed.bin").await,
+            Err(FileError::Io(_))
+        ));
+        assert!(!dir.list_files().await.contains(&"failed.bin".into()));
+        assert_eq!(dir.get_total_bytes(), total);
+    });
+}
+
+#[test]
+fn test_paused_uploads_are_claimed_after_restart() {
+    use async_std::io::WriteExt;
+
+    let path = TestDir::new("restart");
+
+    async_std::task::block_on(async {
+        let uc = TestDir::capability();
+        {
+            let dir = path.load();
+            dir.create_resumable_file(&uc, "paused.bin").await.unwrap();
+            let mut writer = dir
+                .resume_file_writer(&uc, "paused.bin", 0, 10)
+                .await
+                .unwrap();
+            writer.write_all(b"01234").await.unwrap();
+            assert_eq!(writer.finalize().await.len(), 1);
+        }
+
+        /* a plain upload must not truncate the partial file of the paused one */
+        let dir = path.load();
+        assert!(matches!(
+            dir.create_file_writer(&uc, "paused.bin", Some(0)).await,
+            Err(FileError::AlreadyExists)
+        ));
+        assert!(matches!(
+            dir.create_resumable_file(&uc, "paused.bin").await,
+            Err(FileError::AlreadyExists)
+        ));
+        assert_eq!(dir.get_resumable_offset("paused.bin").await.unwrap(), 5);
+    });
+}
+
+#[test]
+fn test_stale_partial_files_are_removed() {
+    let path = TestDir::new("janitor");
+
+    async_std::task::block_on(async {
+        let dir = path.load();
+        let uc = TestDir::capability();
+        dir.create_resumable_file(&uc, "paused.bin").await.unwrap();
+        let writer = dir
+            .create_file_writer(&uc, "active.bin", None)
+            .await
+            .unwrap();
+
+        assert!(dir
+            .remove_stale_partial_files(Duration::from_secs(3600))
+            .await
+            .unwrap()
+            .is_empty());
+
+        /* the upload in progress is kept even though it's old enough */
+        let removed = dir
+            .remove_stale_partial_files(Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(removed, [("paused.bin".to_owned(), 0)]);
+        assert_eq!(dir.list_files().await, ["active.bin"]);
+        assert_accounting_matches_disk(&dir);
+
+        writer.finalize().await;
+
+        /* the janitor skips directories with an empty staging directory */
+        assert!(!Directory::has_partial_files(&path));
+        dir.create_resumable_file(&uc, "paused.bin").await.unwrap();
+        assert!(Directory::has_partial_files(&path));
+    });
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+enum Operation {
+    Upload {
+        name: usize,
+        size: usize,
+        announced: Option<usize>,
+        complete: bool,
+    },
+    CreateResumable {
+        name: usize,
+    },
+    Resume {
+        name: usize,
+        size: usize,
+        pause: bool,
+    },
+    Terminate {
+        name: usize,
+    },
+    Delete {
+        name: usize,
+    },
+    RemoveStale,
+}
+
+/// Returns the number of bytes written into files
+#[cfg(test)]
+async fn apply_operation(dir: &Directory, uc: &UploadCapability, op: Operation) -> u64 {
+    use async_std::io::WriteExt;
+
+    let names = ["a", "b", "c", "d"];
+    match op {
+        Operation::Upload {
+            name,
+            size,
+            announced,
+            complete,
+        } => {
+            let Ok(mut writer) = dir
+                .create_file_writer(uc, names[name], announced.map(|a| a as u64))
+                .await
+            else {
+                return 0;
+            };
+            _ = writer.write_all(&vec![b'x'; size]).await;
+            if complete {
+                writer.mark_stream_complete();
+            }
+            let written = writer.get_bytes_really_written();
+            writer.finalize().await;
+            return written;
+        }
+        Operation::CreateResumable { name } => {
+            _ = dir.create_resumable_file(uc, names[name]).await;
+        }
+        Operation::Resume { name, size, pause } => {
+            let Ok(offset) = dir.get_resumable_offset(names[name]).await else {
+                return 0;
+            };
+            let total = offset + size as u64 + pause as u64;
+            let Ok(mut writer) = dir.resume_file_writer(uc, names[name], offset, total).await
+            else {
+                return 0;
+            };
+            _ = writer.write_all(&vec![b'x'; size]).await;
+            let written = writer.get_bytes_really_written();
+            writer.finalize().await;
+            return written;
+        }
+        Operation::Terminate { name } => {
+            _ = dir.terminate_resumable_file(names[name]).await;
+        }
+        Operation::Delete { name } => {
+            _ = dir.delete_file(names[name]).await;
+        }
+        Operation::RemoveStale => {
+            dir.remove_stale_partial_files(Duration::ZERO)
+                .await
+                .unwrap();
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+fn operation_strategy() -> impl proptest::strategy::Strategy<Value = Operation> {
+    use proptest::prelude::*;
+
+    prop_oneof![
+        (
+            0..4usize,
+            0..3000usize,
+            proptest::option::of(0..3000usize),
+            any::<bool>()
+        )
+            .prop_map(|(name, size, announced, complete)| Operation::Upload {
+                name,
+                size,
+                announced,
+                complete
+            }),
+        (0..4usize).prop_map(|name| Operation::CreateResumable { name }),
+        (0..4usize, 0..3000usize, any::<bool>())
+            .prop_map(|(name, size, pause)| Operation::Resume { name, size, pause }),
+        (0..4usize).prop_map(|name| Operation::Terminate { name }),
+        (0..4usize).prop_map(|name| Operation::Delete { name }),
+        Just(Operation::RemoveStale),
+    ]
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    /// Every byte on disk is charged exactly once and the directory never exceeds the limit
+    #[test]
+    fn test_accounting_matches_disk(
+        operations in proptest::collection::vec(operation_strategy(), 1..20),
+        accounting in proptest::prop_oneof![
+            proptest::strategy::Just(Accounting::Apparent),
+            proptest::strategy::Just(Accounting::Allocated)
+        ],
+    ) {
+        static CASE: AtomicU64 = AtomicU64::new(0);
+        let path = TestDir::new(&format!("accounting-{}", CASE.fetch_add(1, Ordering::Relaxed)));
+
+        let limit = 3 * 8192 + 3000;
+        let uc = UploadCapability::new("dir".to_owned(), limit, 100);
+        let total_limit = 2 * 8192 + 3000;
+        let quota = Arc::new(StorageQuota::new(Some(total_limit), None));
+        let dir = Directory::new(path.to_path_buf(), accounting, quota.clone()).unwrap();
+
+        /* the limits leave room for real writes with either accounting */
+        let first = Operation::Upload { name: 0, size: 1000, announced: Some(1000), complete: true };
+        let written = async_std::task::block_on(apply_operation(&dir, &uc, first));
+        proptest::prop_assert_eq!(written, 1000);
+        proptest::prop_assert!(dir.get_total_bytes() > 0);
+
+        for op in operations {
+            async_std::task::block_on(apply_operation(&dir, &uc, op.clone()));
+            let on_disk = Directory::calculate_existing_data_size(&path, accounting).unwrap();
+            proptest::prop_assert!(on_disk <= limit.min(total_limit), "limit exceeded after {:?}", op);
+            proptest::prop_assert_eq!(dir.get_total_bytes(), on_disk, "wrong accounting after {:?}", op);
+            proptest::prop_assert_eq!(quota.used_bytes(), on_disk, "wrong server-wide accounting after {:?}", op);
+        }
+    }
 }
@@ -0,0 +1,42 @@
+use async_std::task;
+use log::{error, info};
+
+use std::time::Duration;
+
+use crate::data::{Directory, DirectoryRegistry};
+
+/// Deletes abandoned unfinished uploads and recounts the storage used by the directories at
+/// startup and then every `interval`, until the server stops. Zero interval means it runs only once.
+pub async fn run(dirs: DirectoryRegistry, max_age: Duration, interval: Duration) {
+    loop {
+        if let Err(e) = remove_abandoned_uploads(&dirs, max_age).await {
+            error!("Removing abandoned uploads failed: {e}");
+        }
+        /* directories changed while not in use are noticed only here */
+        if let Err(e) = dirs.refresh_storage_usage() {
+            error!("Counting the storage used by all directories failed: {e}");
+        }
+        if interval.is_zero() {
+            return;
+        }
+        task::sleep(interval).await;
+    }
+}
+
+async fn remove_abandoned_uploads(
+    dirs: &DirectoryRegistry,
+    max_age: Duration,
+) -> anyhow::Result<()> {
+    for name in dirs.list_directory_names()? {
+        /* directories without unfinished uploads don't have to be loaded */
+        if !Directory::has_partial_files(&dirs.path(&name)) {
+            continue;
+        }
+
+        let dir = dirs.get(&name).await?;
+        for (file, size) in dir.remove_stale_partial_files(max_age).await? {
+            info!("removed abandoned upload {name}/{file} with {size} bytes");
+        }
+    }
+    Ok(())
+}
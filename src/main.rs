@@ -1,38 +1,327 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
 use async_std::task;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use crypto::{CryptoState, KeyFile, KEY_FILE, LEGACY_SALT};
+use data::Accounting;
+use log::warn;
 use web::start_webserver;
 
+mod cli;
 mod crypto;
 mod data;
+mod janitor;
+mod quota;
+mod revocation;
 mod templates;
+mod throttle;
+mod watcher;
 mod web;
+mod zip;
 
 /// HTTP server used for accepting files from friends. Data
 /// data are saved in the working directory
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 pub struct Args {
-    /// Secret token used for cryptographically signing links
-    #[clap(short, long)]
-    secret: String,
+    #[clap(flatten)]
+    keys: KeyArgs,
+
+    /// Options of the server when started without a subcommand
+    #[clap(flatten)]
+    serve: ServeArgs,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Args, Debug)]
+struct KeyArgs {
+    /// Secret token used for cryptographically signing links. Visible to other users
+    /// of the machine, prefer --secret-file or the GIMMEDAT_SECRET environment variable
+    #[clap(short, long, global = true)]
+    secret: Option<String>,
+
+    /// File containing the secret
+    #[clap(long, global = true)]
+    secret_file: Option<PathBuf>,
 
+    /// Retired secret, links created with it remain valid until they expire. Can be repeated.
+    #[clap(long, global = true)]
+    previous_secret: Vec<String>,
+
+    /// File with retired secrets, one per line
+    #[clap(long, global = true)]
+    previous_secrets_file: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
     /// Allow unlimited uploads to a given directory on the root url
     #[clap(long)]
-    public_access: Option<String>,
+    pub public_access: Option<String>,
 
     /// TCP port to listen on
     #[clap(short, long, default_value_t = 3000)]
-    port: u16,
+    pub port: u16,
 
     /// IP to listen on
     #[clap(short, long, default_value = "127.0.0.1")]
-    listen_ip: String,
+    pub listen_ip: String,
+
+    #[clap(short, long, default_value = "http://localhost:3000")]
+    pub base_url: String,
+
+    /// Take client addresses from the X-Forwarded-For header set by this many reverse proxies
+    /// (1 when no number is given). Without a proxy, clients could fake it to avoid throttling
+    /// of failed logins.
+    #[clap(long, value_name = "PROXIES", num_args = 0..=1, default_missing_value = "1")]
+    pub behind_proxy: Option<usize>,
+
+    /// Delete unfinished uploads which were not written to for this long (e.g. 12h or 7d)
+    #[clap(long, default_value = "7d", value_parser = cli::parse_duration)]
+    pub partial_max_age: u64,
+
+    /// How often to look for abandoned unfinished uploads, 0 checks only at startup
+    #[clap(long, default_value = "1h", value_parser = cli::parse_duration)]
+    pub janitor_interval: u64,
+
+    /// How the bytes used by a directory are counted against the data limit
+    #[clap(long, value_enum, default_value_t)]
+    pub accounting: Accounting,
+
+    /// Maximum bytes stored in all directories together, regardless of the links (e.g. 500G)
+    #[clap(long, value_parser = cli::parse_size)]
+    pub total_limit: Option<u64>,
+
+    /// Stop accepting uploads when less space than this is free on the disk (e.g. 10G)
+    #[clap(long, value_parser = cli::parse_size)]
+    pub min_free_space: Option<u64>,
+}
+
+#[derive(clap::Args, Debug)]
+#[group(skip)]
+pub struct GenLinkArgs {
+    /// Name of the directory the files are uploaded to
+    #[clap(long)]
+    pub name: String,
+
+    /// Size limit of the whole directory, e.g. 500M or 10G
+    #[clap(long, value_parser = cli::parse_size)]
+    pub size: u64,
+
+    /// How long the link stays valid, e.g. 3600, 12h or 7d
+    #[clap(long, value_parser = cli::parse_duration)]
+    pub valid_for: u64,
+
+    /// Size limit of a single file
+    #[clap(long, value_parser = cli::parse_size)]
+    pub max_file_size: Option<u64>,
+
+    /// Maximum number of files
+    #[clap(long)]
+    pub max_files: Option<u64>,
+
+    /// Accepted file types, e.g. image/*,video/*,.pdf
+    #[clap(long)]
+    pub accept: Option<String>,
+
+    /// Delay before the link starts accepting uploads
+    #[clap(long, value_parser = cli::parse_duration)]
+    pub opens_in: Option<u64>,
+
+    /// Print the link and its parameters as JSON
+    #[clap(long)]
+    pub json: bool,
 
     #[clap(short, long, default_value = "http://localhost:3000")]
-    base_url: String,
+    pub base_url: String,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the web server, the default when no subcommand is given
+    Serve(ServeArgs),
+    /// Create an upload link without starting the server
+    GenLink(GenLinkArgs),
+    /// Create a key for the JSON admin API, it can be revoked like any link
+    GenApiKey {
+        /// Name of the key shown in logs
+        #[clap(long)]
+        label: String,
+
+        /// How long the key stays valid, e.g. 90d
+        #[clap(long, value_parser = cli::parse_duration)]
+        valid_for: u64,
+    },
+    /// Revoke a link, it stops working immediately, even on a running server
+    Revoke {
+        /// The whole link or just its token
+        link: String,
+    },
+    /// Show what a link grants and whether it still works
+    Inspect {
+        /// The whole link or just its token
+        link: String,
+
+        /// How the bytes used by the directory are counted, the same as the server's option
+        #[clap(long, value_enum, default_value_t)]
+        accounting: Accounting,
+    },
+    /// Create a key file with a random salt in the data directory, so that the same
+    /// secret results in different keys on different servers
+    Init {
+        /// Keep accepting links created before the key file existed
+        #[clap(long)]
+        keep_legacy_links: bool,
+    },
+}
+
+impl KeyArgs {
+    fn secret(&self) -> anyhow::Result<String> {
+        resolve_secret(
+            self.secret.as_deref(),
+            self.secret_file.as_deref(),
+            std::env::var(SECRET_ENV).ok(),
+            std::env::var_os("CREDENTIALS_DIRECTORY").map(PathBuf::from),
+        )
+    }
+
+    fn crypto_state(&self) -> anyhow::Result<CryptoState> {
+        let secret = &self.secret()?;
+        let mut previous = self.previous_secret.clone();
+        if let Some(path) = &self.previous_secrets_file {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            previous.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(str::to_owned),
+            );
+        }
+
+        let key_file = KeyFile::load(Path::new(KEY_FILE))
+            .with_context(|| format!("failed to load the key file {KEY_FILE}"))?;
+        Ok(match key_file {
+            Some(key_file) => {
+                let crypto =
+                    CryptoState::new(secret, &key_file.salt).with_previous_secrets(&previous);
+                if key_file.accept_legacy_links {
+                    previous.insert(0, secret.to_owned());
+                    crypto.with_legacy_secrets(&previous)
+                } else {
+                    crypto
+                }
+            }
+            None => {
+                warn!("no key file found, using the legacy fixed salt; run `gimmedat init`");
+                CryptoState::new(secret, LEGACY_SALT).with_previous_secrets(&previous)
+            }
+        })
+    }
+}
+
+/// Environment variable the secret can be passed in
+const SECRET_ENV: &str = "GIMMEDAT_SECRET";
+
+/// Name of the systemd credential (`LoadCredential=gimmedat-secret:...`) holding the secret
+const SECRET_CREDENTIAL: &str = "gimmedat-secret";
+
+/// Finds the secret. The command line option, the secret file and the environment variable
+/// are explicit sources and at most one of them can be used. The systemd credential is only
+/// used when none of them is given.
+fn resolve_secret(
+    arg: Option<&str>,
+    file: Option<&Path>,
+    env: Option<String>,
+    credentials_dir: Option<PathBuf>,
+) -> anyhow::Result<String> {
+    let read_secret_file = |path: &Path| -> anyhow::Result<String> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read the secret from {}", path.display()))?;
+        Ok(content.trim_end_matches(['\n', '\r']).to_owned())
+    };
+
+    let secret = match (arg, file, env) {
+        (Some(secret), None, None) => secret.to_owned(),
+        (None, Some(path), None) => read_secret_file(path)?,
+        (None, None, Some(secret)) => secret,
+        (None, None, None) => {
+            let credential = credentials_dir
+                .map(|dir| dir.join(SECRET_CREDENTIAL))
+                .filter(|path| path.exists());
+            match credential {
+                Some(path) => read_secret_file(&path)?,
+                None => anyhow::bail!(
+                    "the secret is required, use --secret-file, {SECRET_ENV} or --secret"
+                ),
+            }
+        }
+        _ => anyhow::bail!(
+            "the secret was given more than once, use only one of --secret, --secret-file and {SECRET_ENV}"
+        ),
+    };
+
+    if secret.is_empty() {
+        anyhow::bail!("the secret must not be empty");
+    }
+    Ok(secret)
 }
 
 fn main() -> tide::Result<()> {
     let args = Args::parse();
-    task::block_on(start_webserver(args))
+    match args.command {
+        None => task::block_on(start_webserver(args.serve, &args.keys)),
+        Some(Command::Serve(serve)) => task::block_on(start_webserver(serve, &args.keys)),
+        Some(Command::GenLink(gen)) => Ok(cli::gen_link(&args.keys.crypto_state()?, gen)?),
+        Some(Command::GenApiKey { label, valid_for }) => Ok(cli::gen_api_key(
+            &args.keys.crypto_state()?,
+            label,
+            valid_for,
+        )?),
+        Some(Command::Revoke { link }) => Ok(cli::revoke_link(&args.keys.crypto_state()?, &link)?),
+        Some(Command::Inspect { link, accounting }) => Ok(cli::inspect_link(
+            &args.keys.crypto_state()?,
+            &link,
+            accounting,
+        )?),
+        Some(Command::Init { keep_legacy_links }) => Ok(cli::init_key_file(keep_legacy_links)?),
+    }
+}
+
+#[test]
+fn test_secret_sources() {
+    let dir = std::env::temp_dir().join(format!("gimmedat-secret-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("secret");
+    std::fs::write(&file, "from file\n").unwrap();
+    std::fs::write(dir.join(SECRET_CREDENTIAL), "from credential\n").unwrap();
+
+    let env = || Some("from env".to_owned());
+    assert_eq!(
+        resolve_secret(Some("arg"), None, None, None).unwrap(),
+        "arg"
+    );
+    assert_eq!(
+        resolve_secret(None, Some(&file), None, None).unwrap(),
+        "from file"
+    );
+    assert_eq!(resolve_secret(None, None, env(), None).unwrap(), "from env");
+    assert_eq!(
+        resolve_secret(None, None, None, Some(dir.clone())).unwrap(),
+        "from credential"
+    );
+    assert_eq!(
+        resolve_secret(None, None, env(), Some(dir.clone())).unwrap(),
+        "from env"
+    );
+    assert!(resolve_secret(Some("arg"), None, env(), None).is_err());
+    assert!(resolve_secret(Some("arg"), Some(&file), None, None).is_err());
+    assert!(resolve_secret(None, None, None, None).is_err());
+    assert!(resolve_secret(Some(""), None, None, None).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
 }
@@ -0,0 +1,251 @@
+use log::{info, warn};
+use nix::sys::statvfs::statvfs;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The free space is checked again after this long...
+const FREE_SPACE_MAX_AGE: Duration = Duration::from_secs(1);
+/// ...or after this many bytes were reserved since the last check
+const FREE_SPACE_MAX_RESERVED: u64 = 16 * 1024 * 1024;
+
+#[derive(Default)]
+struct DirectoryCharge {
+    /// number of loaded `Directory` objects, for a moment there can be a new one while the old one is being dropped
+    loaded: usize,
+    /// bytes of the directory counted from the disk while it's not loaded
+    unloaded_bytes: u64,
+    /// last check of the free space on the filesystem of the directory
+    free_space: Option<FreeSpace>,
+}
+
+/// Free space found out by the last check, the bytes reserved since then are subtracted from it
+struct FreeSpace {
+    bytes: u64,
+    reserved: u64,
+    checked: Instant,
+    /// the check failed, `bytes` are the last known free space
+    failed: bool,
+}
+
+impl FreeSpace {
+    fn available(&self) -> u64 {
+        self.bytes.saturating_sub(self.reserved)
+    }
+
+    fn is_stale(&self) -> bool {
+        self.checked.elapsed() >= FREE_SPACE_MAX_AGE || self.reserved >= FREE_SPACE_MAX_RESERVED
+    }
+}
+
+/// Server-wide limits on the storage used by all directories together. Loaded directories
+/// report every change of their charge here, the other ones are counted from the disk.
+#[derive(Default)]
+pub struct StorageQuota {
+    used: AtomicU64,
+    total_limit: Option<u64>,
+    min_free_space: Option<u64>,
+    directories: Mutex<HashMap<PathBuf, DirectoryCharge>>,
+}
+
+impl StorageQuota {
+    pub fn new(total_limit: Option<u64>, min_free_space: Option<u64>) -> Self {
+        Self {
+            total_limit,
+            min_free_space,
+            ..Default::default()
+        }
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Bytes which can be stored in the directory without violating any of the server-wide limits
+    pub fn remaining_bytes(&self, path: &Path) -> u64 {
+        let mut remaining = u64::MAX;
+        if let Some(limit) = self.total_limit {
+            remaining = limit.saturating_sub(self.used_bytes());
+        }
+        if let Some(min_free) = self.min_free_space {
+            let mut directories = self.directories.lock().unwrap();
+            let charge = directories.entry(path.to_owned()).or_default();
+            remaining = remaining.min(
+                free_space(charge, path)
+                    .available()
+                    .saturating_sub(min_free),
+            );
+        }
+        remaining
+    }
+
+    /// Charges the bytes only when they fit within the limits
+    pub fn reserve(&self, bytes: u64, path: &Path) -> bool {
+        let Some(min_free) = self.min_free_space else {
+            return self.reserve_total(bytes);
+        };
+
+        /* the reserved bytes are subtracted from the free space until it's checked again */
+        let mut directories = self.directories.lock().unwrap();
+        let charge = directories.entry(path.to_owned()).or_default();
+        let free = free_space(charge, path);
+        if free.available() < min_free.saturating_add(bytes) || !self.reserve_total(bytes) {
+            return false;
+        }
+        free.reserved += bytes;
+        true
+    }
+
+    fn reserve_total(&self, bytes: u64) -> bool {
+        match self.total_limit {
+            None => {
+                self.add(bytes);
+                true
+            }
+            Some(limit) => self
+                .used
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                    (used + bytes <= limit).then_some(used + bytes)
+                })
+                .is_ok(),
+        }
+    }
+
+    pub fn add(&self, bytes: u64) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn release(&self, bytes: u64) {
+        _ = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                Some(v.saturating_sub(bytes))
+            });
+    }
+
+    /// Counts a freshly loaded directory with its charge, instead of what was counted from the disk
+    pub fn load(&self, path: &Path, bytes: u64) {
+        let mut directories = self.directories.lock().unwrap();
+        let charge = directories.entry(path.to_owned()).or_default();
+        if charge.loaded == 0 {
+            self.release(std::mem::take(&mut charge.unloaded_bytes));
+        }
+        charge.loaded += 1;
+        self.add(bytes);
+    }
+
+    /// Keeps counting the last charge of a dropped directory
+    pub fn unload(&self, path: &Path, bytes: u64) {
+        let mut directories = self.directories.lock().unwrap();
+        let charge = directories.entry(path.to_owned()).or_default();
+        charge.loaded = charge.loaded.saturating_sub(1);
+        if charge.loaded == 0 {
+            charge.unloaded_bytes = bytes;
+        } else {
+            /* the directory was loaded again in the meantime, that one is counted instead */
+            self.release(bytes);
+        }
+    }
+
+    /// Replaces the charges of the directories which are not loaded with their sizes on the disk,
+    /// directories missing in `sizes` don't exist anymore
+    pub fn refresh_unloaded(&self, sizes: HashMap<PathBuf, u64>) {
+        let mut directories = self.directories.lock().unwrap();
+        directories.retain(|path, charge| {
+            if charge.loaded > 0 || sizes.contains_key(path) {
+                return true;
+            }
+            self.release(charge.unloaded_bytes);
+            false
+        });
+
+        for (path, bytes) in sizes {
+            let charge = directories.entry(path).or_default();
+            if charge.loaded == 0 {
+                self.release(charge.unloaded_bytes);
+                charge.unloaded_bytes = bytes;
+                self.add(bytes);
+            }
+        }
+    }
+}
+
+/// Space available to unprivileged users on the filesystem of the directory. The filesystem
+/// is asked only when the last check is stale, uploads reserve the space in small chunks.
+#[allow(clippy::unnecessary_cast)] // the types are narrower on 32-bit platforms
+fn free_space<'a>(charge: &'a mut DirectoryCharge, path: &Path) -> &'a mut FreeSpace {
+    if charge
+        .free_space
+        .as_ref()
+        .is_some_and(|free| !free.is_stale())
+    {
+        return charge.free_space.as_mut().unwrap();
+    }
+
+    let last = charge.free_space.take();
+    let last_failed = last.as_ref().is_some_and(|free| free.failed);
+    let (bytes, failed) = match statvfs(path) {
+        Ok(stat) => {
+            if last_failed {
+                info!("The free space of {path:?} can be found out again");
+            }
+            (
+                stat.blocks_available() as u64 * stat.fragment_size() as u64,
+                false,
+            )
+        }
+        Err(e) => {
+            /* reported only once, the check is repeated for every stale use */
+            if !last_failed {
+                warn!("Failed to find out the free space of {path:?}, the last known value is used instead: {e}");
+            }
+            (last.as_ref().map_or(u64::MAX, FreeSpace::available), true)
+        }
+    };
+    charge.free_space.insert(FreeSpace {
+        bytes,
+        reserved: 0,
+        checked: Instant::now(),
+        failed,
+    })
+}
+
+#[test]
+fn test_directories_are_counted_once() {
+    let quota = StorageQuota::new(Some(1000), None);
+    let path = Path::new("dir");
+    quota.refresh_unloaded(HashMap::from([
+        (path.to_owned(), 300),
+        ("other".into(), 100),
+    ]));
+    assert_eq!(quota.used_bytes(), 400);
+
+    /* the old directory object is dropped only after a new one was loaded */
+    quota.load(path, 350);
+    quota.load(path, 360);
+    quota.unload(path, 350);
+    assert_eq!(quota.used_bytes(), 460);
+
+    assert!(!quota.reserve(600, path));
+    assert!(quota.reserve(540, path));
+    quota.unload(path, 900);
+    quota.refresh_unloaded(HashMap::from([(path.to_owned(), 200)]));
+    assert_eq!(quota.used_bytes(), 200);
+    assert_eq!(quota.remaining_bytes(path), 800);
+}
+
+#[test]
+fn test_reservations_lower_the_free_space() {
+    let path = std::env::temp_dir();
+    let quota = StorageQuota::new(None, Some(0));
+    let free = quota.remaining_bytes(&path);
+
+    /* nothing was written, the reservation is subtracted from the last check */
+    assert!(quota.reserve(1024 * 1024, &path));
+    assert!(quota.remaining_bytes(&path) <= free - 1024 * 1024);
+    assert!(!quota.reserve(free, &path));
+    assert_eq!(quota.used_bytes(), 1024 * 1024);
+}
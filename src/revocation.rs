@@ -0,0 +1,115 @@
+use log::{error, info};
+
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File in the data root storing IDs of revoked links, one per line
+pub const REVOCATION_FILE: &str = ".gimmedat-revoked";
+
+/// Persistent list of revoked links. The file is reloaded whenever it changes,
+/// so links revoked from the command line apply to a running server as well.
+#[derive(Clone)]
+pub struct RevocationList {
+    inner: Arc<Mutex<RevocationListState>>,
+}
+
+struct RevocationListState {
+    path: PathBuf,
+    ids: HashSet<String>,
+    loaded_mtime: Option<SystemTime>,
+}
+
+impl RevocationListState {
+    fn reload_if_changed(&mut self) {
+        let mtime = std::fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .ok();
+        if mtime == self.loaded_mtime {
+            return;
+        }
+
+        self.ids = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .filter_map(|l| l.split_whitespace().next())
+                .map(str::to_owned)
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => {
+                /* keep the previous state, failing open would make revoked links valid again */
+                error!("Error reading the revocation list: {e}");
+                return;
+            }
+        };
+        self.loaded_mtime = mtime;
+    }
+}
+
+impl RevocationList {
+    pub fn new(path: PathBuf) -> Self {
+        let mut state = RevocationListState {
+            path,
+            ids: HashSet::new(),
+            loaded_mtime: None,
+        };
+        state.reload_if_changed();
+
+        Self {
+            inner: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub fn is_revoked(&self, token_id: &str) -> bool {
+        let mut state = self.inner.lock().unwrap();
+        state.reload_if_changed();
+        state.ids.contains(token_id)
+    }
+
+    /// Adds the token ID to the list, returns false if it was already revoked
+    pub fn revoke(&self, token_id: &str) -> std::io::Result<bool> {
+        let mut state = self.inner.lock().unwrap();
+        state.reload_if_changed();
+        if state.ids.contains(token_id) {
+            return Ok(false);
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&state.path)?;
+        writeln!(file, "{token_id} {timestamp}")?;
+        file.sync_all()?;
+
+        info!("revoked link {token_id}");
+        state.ids.insert(token_id.to_owned());
+        Ok(true)
+    }
+}
+
+#[test]
+fn test_revocation_is_persisted() {
+    let path = std::env::temp_dir().join(format!("gimmedat-revoked-test-{}", std::process::id()));
+    _ = std::fs::remove_file(&path);
+
+    let list = RevocationList::new(path.clone());
+    assert!(!list.is_revoked("abc"));
+    assert!(list.revoke("abc").unwrap());
+    assert!(!list.revoke("abc").unwrap());
+    assert!(list.is_revoked("abc"));
+
+    let reloaded = RevocationList::new(path.clone());
+    assert!(reloaded.is_revoked("abc"));
+    assert!(!reloaded.is_revoked("def"));
+
+    std::fs::remove_file(&path).unwrap();
+}
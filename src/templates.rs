@@ -1,18 +1,45 @@
+use std::ffi::OsString;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use askama::Template;
 
-use crate::data::{Directory, UploadCapability};
+use crate::cli::format_duration;
+use crate::data::{Directory, DirectorySummary, DownloadCapability, UploadCapability};
 
 #[derive(Template)]
 #[template(path = "index.html.j2")]
 pub struct IndexTemplate {
     invalid_secret: bool,
+    message: Option<String>,
+    error: Option<String>,
+    /// CSRF token of the session, the admin forms are shown only when logged in
+    csrf: Option<String>,
 }
 
 impl IndexTemplate {
     pub fn new(invalid_secret: bool) -> Self {
-        Self { invalid_secret }
+        Self {
+            invalid_secret,
+            message: None,
+            error: None,
+            csrf: None,
+        }
+    }
+
+    pub fn with_session(mut self, csrf: Option<String>) -> Self {
+        self.csrf = csrf;
+        self
+    }
+
+    pub fn with_message(mut self, message: String) -> Self {
+        self.message = Some(message);
+        self
+    }
+
+    pub fn with_error(mut self, error: String) -> Self {
+        self.error = Some(error);
+        self
     }
 }
 
@@ -20,9 +47,15 @@ impl IndexTemplate {
 #[template(path = "upload.html.j2")]
 pub struct UploadHelpTemplate<'a> {
     remaining_sec: u64,
+    opens_in_sec: u64,
     maxsize_bytes: u64,
+    max_file_size_bytes: Option<u64>,
+    max_files: Option<u64>,
+    accepted_file_types: Option<&'a str>,
     url: &'a str,
     uploaded_files: Vec<String>,
+    /// the page uploads using tus, which is not available for the public upload page
+    resumable: bool,
 }
 
 impl<'a> UploadHelpTemplate<'a> {
@@ -37,7 +70,11 @@ impl<'a> UploadHelpTemplate<'a> {
             } else {
                 cap.remaining_time_secs()
             },
+            opens_in_sec: cap.time_until_active_secs(),
             maxsize_bytes: dir.get_remaining_bytes(cap),
+            max_file_size_bytes: cap.file_size_limit(),
+            max_files: cap.file_count_limit(),
+            accepted_file_types: cap.accepted_file_types(),
             url,
             uploaded_files: dir
                 .list_files()
@@ -48,6 +85,143 @@ impl<'a> UploadHelpTemplate<'a> {
                         .unwrap_or("INVALID UTF8 FILENAME".to_owned())
                 })
                 .collect(),
+            resumable: true,
+        }
+    }
+
+    pub fn without_resumable_uploads(mut self) -> Self {
+        self.resumable = false;
+        self
+    }
+}
+
+struct DownloadableFile {
+    name: String,
+    size: u64,
+}
+
+#[derive(Template)]
+#[template(path = "download.html.j2")]
+pub struct DownloadTemplate<'a> {
+    remaining_days: u64,
+    url: &'a str,
+    zip_url: &'a str,
+    files: Vec<DownloadableFile>,
+}
+
+impl<'a> DownloadTemplate<'a> {
+    pub fn new(
+        url: &'a str,
+        zip_url: &'a str,
+        cap: &DownloadCapability,
+        files: Vec<(OsString, u64)>,
+    ) -> Self {
+        Self {
+            remaining_days: if cap.is_expired() {
+                0
+            } else {
+                cap.remaining_time_secs() / (24 * 3600)
+            },
+            url,
+            zip_url,
+            files: files
+                .into_iter()
+                .map(|(name, size)| DownloadableFile {
+                    name: name
+                        .into_string()
+                        .unwrap_or("INVALID UTF8 FILENAME".to_owned()),
+                    size,
+                })
+                .collect(),
+        }
+    }
+}
+
+fn time_ago(time: SystemTime) -> String {
+    let secs = SystemTime::now()
+        .duration_since(time)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!("{} ago", format_duration(secs))
+}
+
+struct PartialUploadRow {
+    name: String,
+    size: u64,
+    idle: String,
+}
+
+struct DirectoryRow {
+    name: String,
+    browse_url: String,
+    files: usize,
+    total_bytes: u64,
+    last_upload: String,
+    partial_uploads: Vec<PartialUploadRow>,
+}
+
+#[derive(Template)]
+#[template(path = "admin.html.j2")]
+pub struct AdminTemplate {
+    directories: Vec<DirectoryRow>,
+    total_bytes: u64,
+}
+
+impl AdminTemplate {
+    /// Takes summaries of the directories together with links for browsing them
+    pub fn new(directories: Vec<(DirectorySummary, String)>) -> Self {
+        let directories: Vec<DirectoryRow> = directories
+            .into_iter()
+            .map(|(summary, browse_url)| DirectoryRow {
+                name: summary.name,
+                browse_url,
+                files: summary.files,
+                total_bytes: summary.total_bytes,
+                last_upload: summary
+                    .last_upload
+                    .map(time_ago)
+                    .unwrap_or_else(|| "never".to_owned()),
+                partial_uploads: summary
+                    .partial_uploads
+                    .into_iter()
+                    .map(|p| PartialUploadRow {
+                        name: p.name,
+                        size: p.size,
+                        idle: time_ago(p.modified),
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        Self {
+            total_bytes: directories.iter().map(|d| d.total_bytes).sum(),
+            directories,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin_directory.html.j2")]
+pub struct AdminDirectoryTemplate {
+    name: String,
+    csrf: String,
+    files: Vec<DownloadableFile>,
+}
+
+impl AdminDirectoryTemplate {
+    pub fn new(name: String, csrf: String, files: Vec<(OsString, u64)>) -> Self {
+        Self {
+            name,
+            csrf,
+            files: files
+                .into_iter()
+                .map(|(name, size)| DownloadableFile {
+                    name: name
+                        .into_string()
+                        .unwrap_or("INVALID UTF8 FILENAME".to_owned()),
+                    size,
+                })
+                .collect(),
         }
     }
 }
@@ -67,3 +241,19 @@ impl UploadResponseTemplate {
         }
     }
 }
+
+#[test]
+fn test_file_names_are_not_executed() {
+    let name = "x');alert(document.cookie);('.txt";
+    let page = AdminDirectoryTemplate::new(
+        "dir".to_owned(),
+        "csrf".to_owned(),
+        vec![(OsString::from(name), 1)],
+    )
+    .render()
+    .unwrap();
+
+    assert!(!page.contains(name));
+    assert!(page.contains("onsubmit=\"return confirm('Delete ' + this.dataset.name + '?')\""));
+    assert!(page.contains("data-name=\"x&#x27;);alert(document.cookie);(&#x27;.txt\""));
+}
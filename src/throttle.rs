@@ -0,0 +1,207 @@
+use async_std::channel::{bounded, Receiver, Sender};
+use async_std::task;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Number of failed attempts allowed before the client has to wait
+const FREE_ATTEMPTS: u32 = 3;
+
+/// Upper bound of the waiting time between attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
+/// Number of clients tracked before forgotten ones are cleaned up
+const CLEANUP_THRESHOLD: usize = 10_000;
+
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+/// Tracks failed secret attempts per client. After a few failures, the client has to wait
+/// exponentially longer before it's allowed to try again.
+#[derive(Clone, Default)]
+pub struct LoginThrottle {
+    clients: Arc<Mutex<HashMap<String, Failures>>>,
+}
+
+fn backoff(failures: u32) -> Duration {
+    if failures < FREE_ATTEMPTS {
+        return Duration::ZERO;
+    }
+    let exp = (failures - FREE_ATTEMPTS).min(20);
+    Duration::from_secs(1 << exp).min(MAX_BACKOFF)
+}
+
+impl LoginThrottle {
+    /// Starts an attempt of the client, unless it has to wait. The attempt counts as failed
+    /// until [`LoginThrottle::record_success`] is called, so that concurrent attempts can't
+    /// all pass the check before the first one fails.
+    pub fn begin_attempt(&self, client: &str) -> Result<(), Duration> {
+        self.begin_attempt_at(client, Instant::now())
+    }
+
+    fn begin_attempt_at(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(f) = clients.get(client) {
+            let allowed_at = f.last + backoff(f.count);
+            if allowed_at > now {
+                return Err(allowed_at - now);
+            }
+        }
+
+        if clients.len() >= CLEANUP_THRESHOLD {
+            clients.retain(|_, f| now.saturating_duration_since(f.last) < MAX_BACKOFF);
+        }
+        let f = clients.entry(client.to_owned()).or_insert(Failures {
+            count: 0,
+            last: now,
+        });
+        f.count = f.count.saturating_add(1);
+        f.last = now;
+        Ok(())
+    }
+
+    pub fn record_success(&self, client: &str) {
+        self.clients.lock().unwrap().remove(client);
+    }
+}
+
+/// Limits the number of concurrently running key derivations, so that a flood of requests
+/// can't occupy all CPUs. The derivations run on the blocking thread pool, only a limited
+/// number of them can wait for a free slot.
+#[derive(Clone)]
+pub struct DerivationPool {
+    slots: (Sender<()>, Receiver<()>),
+    /// derivations running or waiting for a slot
+    pending: Arc<AtomicUsize>,
+    max_pending: usize,
+}
+
+/// There are too many derivations waiting already
+#[derive(Debug)]
+pub struct PoolFull;
+
+/// A pending derivation, its slot is returned and it stops being counted when dropped,
+/// even when the request waiting for it was cancelled
+struct PendingDerivation {
+    slots: Sender<()>,
+    pending: Arc<AtomicUsize>,
+    has_slot: bool,
+}
+
+impl Drop for PendingDerivation {
+    fn drop(&mut self) {
+        if self.has_slot {
+            _ = self.slots.try_send(());
+        }
+        self.pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl DerivationPool {
+    pub fn new(size: usize, max_pending: usize) -> Self {
+        let (tx, rx) = bounded(size);
+        for _ in 0..size {
+            tx.try_send(()).expect("the channel has room for all slots");
+        }
+        DerivationPool {
+            slots: (tx, rx),
+            pending: Arc::default(),
+            max_pending,
+        }
+    }
+
+    pub async fn run<T, F>(&self, f: F) -> Result<T, PoolFull>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        if self.pending.fetch_add(1, Ordering::Relaxed) >= self.max_pending {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+            return Err(PoolFull);
+        }
+        let mut derivation = PendingDerivation {
+            slots: self.slots.0.clone(),
+            pending: self.pending.clone(),
+            has_slot: false,
+        };
+
+        self.slots.1.recv().await.expect("the pool owns a sender");
+        derivation.has_slot = true;
+
+        /* the slot is held until the derivation really ends, not just until the request is gone */
+        Ok(task::spawn_blocking(move || {
+            let _derivation = derivation;
+            f()
+        })
+        .await)
+    }
+}
+
+#[test]
+fn test_backoff_grows_and_resets() {
+    let throttle = LoginThrottle::default();
+    let start = Instant::now();
+    for _ in 0..FREE_ATTEMPTS {
+        assert!(throttle.begin_attempt_at("a", start).is_ok());
+    }
+    assert_eq!(
+        throttle.begin_attempt_at("a", start),
+        Err(Duration::from_secs(1))
+    );
+    assert!(throttle
+        .begin_attempt_at("a", start + Duration::from_secs(1))
+        .is_ok());
+    assert!(throttle.begin_attempt_at("b", start).is_ok());
+
+    let later = start + Duration::from_secs(1);
+    assert_eq!(
+        throttle.begin_attempt_at("a", later),
+        Err(Duration::from_secs(2))
+    );
+    let mut now = later;
+    for _ in 0..30 {
+        now += MAX_BACKOFF;
+        assert!(throttle.begin_attempt_at("a", now).is_ok());
+    }
+    assert_eq!(throttle.begin_attempt_at("a", now), Err(MAX_BACKOFF));
+
+    throttle.record_success("a");
+    assert!(throttle.begin_attempt_at("a", now).is_ok());
+}
+
+#[test]
+fn test_derivation_pool_is_bounded() {
+    let pool = DerivationPool::new(1, 2);
+    let (release, blocked) = std::sync::mpsc::channel::<()>();
+
+    task::block_on(async {
+        let running = task::spawn({
+            let pool = pool.clone();
+            async move { pool.run(move || blocked.recv().unwrap()).await }
+        });
+        while pool.pending.load(Ordering::Relaxed) < 1 {
+            task::yield_now().await;
+        }
+
+        /* a cancelled request must not take the slot with it */
+        let waiting = task::spawn({
+            let pool = pool.clone();
+            async move { pool.run(|| ()).await }
+        });
+        while pool.pending.load(Ordering::Relaxed) < 2 {
+            task::yield_now().await;
+        }
+        assert!(pool.run(|| ()).await.is_err());
+        waiting.cancel().await;
+        assert_eq!(pool.pending.load(Ordering::Relaxed), 1);
+
+        release.send(()).unwrap();
+        running.await.unwrap();
+        assert_eq!(pool.run(|| 42).await.unwrap(), 42);
+        assert_eq!(pool.pending.load(Ordering::Relaxed), 0);
+    });
+}
@@ -0,0 +1,151 @@
+use async_std::task;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask, Watches};
+use log::{debug, error, warn};
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::data::{Directory, STAGING_DIR};
+
+/// Changes are reconciled once the directory was quiet for this long
+const SETTLE_DELAY: Duration = Duration::from_secs(1);
+
+/// Changes which alter the set of files or their sizes
+const WATCHED_EVENTS: WatchMask = WatchMask::CREATE
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::CLOSE_WRITE)
+    .union(WatchMask::DELETE_SELF)
+    .union(WatchMask::MOVE_SELF)
+    .union(WatchMask::ONLYDIR);
+
+struct Watched {
+    dir: Weak<Directory>,
+    path: PathBuf,
+}
+
+/// Watches loaded directories with inotify, so that files deleted, moved or written by
+/// someone else than the server are reflected in the filename set and the byte accounting.
+#[derive(Clone)]
+pub struct DirectoryWatcher {
+    watches: Watches,
+    watched: Arc<Mutex<HashMap<WatchDescriptor, Watched>>>,
+}
+
+impl DirectoryWatcher {
+    pub fn start() -> std::io::Result<Self> {
+        let inotify = Inotify::init()?;
+        let watcher = Self {
+            watches: inotify.watches(),
+            watched: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        let cloned = watcher.clone();
+        thread::Builder::new()
+            .name("directory-watcher".to_owned())
+            .spawn(move || cloned.run(inotify))?;
+
+        Ok(watcher)
+    }
+
+    /// Starts watching the directory, the watch is removed after the directory is dropped
+    pub fn watch(&self, dir: &Arc<Directory>) {
+        let mut watched = self.watched.lock().unwrap();
+
+        /* forget directories which are not loaded anymore */
+        let dropped: Vec<WatchDescriptor> = watched
+            .iter()
+            .filter(|(_, w)| w.dir.strong_count() == 0)
+            .map(|(wd, _)| wd.clone())
+            .collect();
+        for wd in dropped {
+            watched.remove(&wd);
+            _ = self.watches.clone().remove(wd);
+        }
+
+        /* unfinished uploads in the staging directory are written only by the server itself */
+        let path = dir.path().to_owned();
+        match self.watches.clone().add(&path, WATCHED_EVENTS) {
+            Ok(wd) => {
+                watched.insert(
+                    wd,
+                    Watched {
+                        dir: Arc::downgrade(dir),
+                        path,
+                    },
+                );
+            }
+            Err(e) => warn!("Failed to watch directory {path:?} for changes: {e}"),
+        }
+    }
+
+    fn run(self, mut inotify: Inotify) {
+        let mut buffer = [0u8; 4096];
+        /* directories with unreconciled changes and the time of the last change */
+        let mut pending: HashMap<PathBuf, (Weak<Directory>, Instant)> = HashMap::new();
+
+        loop {
+            let events = if pending.is_empty() {
+                inotify.read_events_blocking(&mut buffer)
+            } else {
+                inotify.read_events(&mut buffer)
+            };
+
+            match events {
+                Ok(events) => {
+                    let mut watched = self.watched.lock().unwrap();
+                    for event in events {
+                        let Some(w) = watched.get(&event.wd) else {
+                            continue;
+                        };
+
+                        /* the server's own changes, the staging directory and files being uploaded */
+                        let own = match (event.name, w.dir.upgrade()) {
+                            (Some(name), Some(dir)) => {
+                                name == STAGING_DIR || task::block_on(dir.is_being_written(name))
+                            }
+                            _ => false,
+                        };
+                        if !own {
+                            pending.insert(w.path.clone(), (w.dir.clone(), Instant::now()));
+                        }
+
+                        /* the directory itself is gone, the watch was removed by the kernel */
+                        if event.mask.contains(EventMask::IGNORED) {
+                            watched.remove(&event.wd);
+                        }
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    error!("Watching directories for changes failed: {e}");
+                    return;
+                }
+            }
+
+            pending.retain(|path, (dir, last_change)| {
+                if last_change.elapsed() < SETTLE_DELAY {
+                    return true;
+                }
+                let Some(dir) = dir.upgrade() else {
+                    return false;
+                };
+
+                debug!("Reconciling directory {path:?} with changes on disk");
+                if let Err(e) = task::block_on(dir.reconcile()) {
+                    error!("Failed to reconcile directory {path:?}: {e}");
+                }
+                false
+            });
+
+            if !pending.is_empty() {
+                thread::sleep(SETTLE_DELAY / 4);
+            }
+        }
+    }
+}
@@ -1,36 +1,93 @@
-use crate::crypto::CryptoState;
-use crate::data::{DirectoryRegistry, UploadCapability};
-use crate::templates::{IndexTemplate, UploadHelpTemplate, UploadResponseTemplate};
-use crate::Args;
-use async_std::io::copy;
-use log::warn;
+use crate::crypto::{constant_time_eq, token_id, CryptoState};
+use crate::data::{
+    validate_dir_name, validate_file_name, ApiKeyCapability, Directory, DirectoryRegistry,
+    DownloadCapability, FileError, ResumableUpload, SessionCapability, UploadCapability,
+};
+use crate::janitor;
+use crate::quota::StorageQuota;
+use crate::revocation::{RevocationList, REVOCATION_FILE};
+use crate::templates::{
+    AdminDirectoryTemplate, AdminTemplate, DownloadTemplate, IndexTemplate, UploadHelpTemplate,
+    UploadResponseTemplate,
+};
+use crate::throttle::{DerivationPool, LoginThrottle};
+use crate::zip::ZipStream;
+use crate::{KeyArgs, ServeArgs};
+use async_std::io::{copy, prelude::SeekExt, BufReader, ReadExt};
+use async_std::task;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use log::{info, warn};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
 use rand_core::{OsRng, RngCore};
 use std::error::Error;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::pin::Pin;
 use std::str;
-use tide::{utils::After, Request};
+use std::sync::Arc;
+use std::time::Duration;
+use tide::http::cookies::{Cookie, SameSite};
+use tide::{http::Mime, utils::After, Body, Next, Redirect, Request, Response};
 
-use serde_derive::Deserialize;
+use serde::{Deserialize as _, Deserializer};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::str::FromStr;
 
 #[derive(Clone)]
 struct Context {
     crypto: CryptoState,
     dirs: DirectoryRegistry,
+    revoked: RevocationList,
+    throttle: LoginThrottle,
+    derivations: DerivationPool,
+    /// number of reverse proxies in front of the server, which add to X-Forwarded-For
+    trusted_proxies: usize,
     base_url: String,
     public_dir: Option<String>,
 }
 
+/// Number of secret checks running at the same time, each one runs the expensive Argon2
+const MAX_CONCURRENT_DERIVATIONS: usize = 2;
+
+/// Number of secret checks running or waiting, more are refused right away
+const MAX_PENDING_DERIVATIONS: usize = 32;
+
 impl Context {
-    fn new(secret: &str, base_url: String, public_dir: Option<String>) -> Self {
+    fn new(
+        crypto: CryptoState,
+        base_url: String,
+        public_dir: Option<String>,
+        dirs: DirectoryRegistry,
+    ) -> Self {
         Context {
-            crypto: CryptoState::new(secret),
+            crypto,
             base_url,
             public_dir,
-            dirs: DirectoryRegistry::new(),
+            revoked: RevocationList::new(dirs.root().join(REVOCATION_FILE)),
+            throttle: LoginThrottle::default(),
+            derivations: DerivationPool::new(MAX_CONCURRENT_DERIVATIONS, MAX_PENDING_DERIVATIONS),
+            trusted_proxies: 0,
+            dirs,
         }
     }
 
+    fn trust_proxy_headers(mut self, trusted_proxies: usize) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
     fn create_link(&self, token: &str) -> String {
-        format!("{}/{}/", self.base_url, token)
+        upload_link(&self.base_url, token)
+    }
+
+    fn create_download_link(&self, token: &str) -> String {
+        format!("{}/dl/{}/", self.base_url, token)
+    }
+
+    fn create_zip_link(&self, token: &str) -> String {
+        format!("{}/zip/{}", self.base_url, token)
     }
 
     fn create_public_link(&self) -> String {
@@ -58,15 +115,66 @@ impl Context {
     }
 }
 
-pub async fn start_webserver(args: Args) -> tide::Result<()> {
+pub fn upload_link(base_url: &str, token: &str) -> String {
+    format!("{}/{}/", base_url, token)
+}
+
+/// Description of a newly created upload link, returned by the API and `gen-link --json`
+#[derive(Serialize, Debug)]
+pub struct UploadLinkInfo {
+    link: String,
+    token_id: String,
+    directory: String,
+    size_limit: u64,
+    file_size_limit: Option<u64>,
+    file_count_limit: Option<u64>,
+    accepted_file_types: Option<String>,
+    opens_at: Option<u64>,
+    expires_at: u64,
+}
+
+impl UploadLinkInfo {
+    pub fn new(link: String, token: &str, cap: &UploadCapability) -> Self {
+        UploadLinkInfo {
+            link,
+            token_id: token_id(token).expect("freshly encrypted token is always valid"),
+            directory: cap.dir_name().to_owned(),
+            size_limit: cap.size_limit(),
+            file_size_limit: cap.file_size_limit(),
+            file_count_limit: cap.file_count_limit(),
+            accepted_file_types: cap.accepted_file_types().map(str::to_owned),
+            opens_at: cap.activation_timestamp(),
+            expires_at: cap.expiration_timestamp(),
+        }
+    }
+}
+
+pub async fn start_webserver(args: ServeArgs, keys: &KeyArgs) -> tide::Result<()> {
     tide::log::start();
 
     let port = args.port;
-    let mut app = tide::with_state(Context::new(
-        &args.secret,
-        args.base_url,
-        args.public_access,
+    let crypto = keys.crypto_state()?;
+    info!("new links are signed with key {}", crypto.current_key_id());
+    let dirs = DirectoryRegistry::new(
+        PathBuf::from("."),
+        args.accounting,
+        StorageQuota::new(args.total_limit, args.min_free_space),
+    );
+    dirs.refresh_storage_usage()?;
+    let ctx = Context::new(crypto, args.base_url, args.public_access, dirs)
+        .trust_proxy_headers(args.behind_proxy.unwrap_or(0));
+    task::spawn(janitor::run(
+        ctx.dirs.clone(),
+        Duration::from_secs(args.partial_max_age),
+        Duration::from_secs(args.janitor_interval),
     ));
+    let app = build_app(ctx);
+    app.listen((args.listen_ip, port)).await?;
+    Ok(())
+}
+
+fn build_app(ctx: Context) -> tide::Server<Context> {
+    let mut app = tide::with_state(ctx);
     app.with(After(|mut res: tide::Response| async {
         if res.error().is_some() {
             let msg = match res.take_error() {
@@ -83,46 +191,512 @@ pub async fn start_webserver(args: Args) -> tide::Result<()> {
     } else {
         app.at("/").get(index);
         app.at("/gen").post(post_gen);
+        app.at("/gen/download").post(post_gen_download);
+        app.at("/revoke").post(post_revoke);
+        app.at("/admin").get(admin).post(post_admin);
+        app.at("/admin/dir/:dir").get(admin_directory);
+        app.at("/admin/dir/:dir/delete").post(admin_delete_file);
+        app.at("/admin/dir/:dir/rename").post(admin_rename_file);
+        app.at("/login").post(post_login);
+        app.at("/logout").post(post_logout);
+        register_api(&mut app);
     }
+    app.at("/dl/:token/").get(download_listing);
+    app.at("/dl/:token/:name").get(download_file);
+    app.at("/zip/:token").get(download_zip);
     app.at("/:token/").put(upload).get(upload_help);
     app.at("/:token/:name").put(upload).get(upload_help);
-    app.listen((args.listen_ip, port)).await?;
-    Ok(())
+    app.at("/:token/tus/").post(tus_create).options(tus_options);
+    app.at("/:token/tus/:id")
+        .head(tus_head)
+        .patch(tus_patch)
+        .delete(tus_delete)
+        .options(tus_options);
+    app
+}
+
+/// Fields authorizing an admin form, see `authorize_form`
+#[derive(Deserialize, Debug)]
+struct FormAuth {
+    // secret, not needed when logged in
+    #[serde(default)]
+    s: String,
+    // CSRF token of the session
+    #[serde(default)]
+    x: String,
 }
 
 #[derive(Deserialize, Debug)]
 struct GenQuery {
     /// dir name where to store the data
     n: String,
-    // secret
-    s: String,
+    #[serde(flatten)]
+    auth: FormAuth,
     /// size limit in bytes
     m: u64,
     /// remaining time
     t: u64,
+    /// size limit of a single file in bytes
+    #[serde(default, deserialize_with = "empty_as_none")]
+    f: Option<u64>,
+    /// maximum number of files
+    #[serde(default, deserialize_with = "empty_as_none")]
+    c: Option<u64>,
+    /// allowed file types
+    #[serde(default, deserialize_with = "empty_as_none")]
+    a: Option<String>,
+    /// delay before the link becomes active
+    #[serde(default, deserialize_with = "empty_as_none")]
+    b: Option<u64>,
+}
+
+/// HTML forms submit empty inputs as empty strings, we treat them as missing values
+fn empty_as_none<'de, D, T>(de: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    match Option::<String>::deserialize(de)?.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(s) => s.parse().map(Some).map_err(serde::de::Error::custom),
+    }
 }
 
 fn token_to_link(ctx: &Context, tok: &UploadCapability) -> String {
     ctx.create_link(&ctx.crypto.encrypt(&tok.to_string()))
 }
 
+#[derive(Deserialize, Debug)]
+struct GenDownloadQuery {
+    /// dir name with the data
+    n: String,
+    #[serde(flatten)]
+    auth: FormAuth,
+    /// remaining time
+    t: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct RevokeQuery {
+    #[serde(flatten)]
+    auth: FormAuth,
+    /// link or token to revoke
+    l: String,
+}
+
+/// Address of the client, failed attempts are throttled per address
+fn client_address(req: &Request<Context>) -> String {
+    let forwarded = match req.state().trusted_proxies {
+        0 => None,
+        proxies => req
+            .header("X-Forwarded-For")
+            .and_then(|values| forwarded_client(values.iter().map(|v| v.as_str()), proxies)),
+    };
+    let addr = forwarded.or(req.peer_addr()).unwrap_or("unknown");
+
+    /* strip the port, the same client connects from different ones */
+    addr.parse::<std::net::SocketAddr>()
+        .map(|a| a.ip().to_string())
+        .unwrap_or_else(|_| addr.to_owned())
+}
+
+/// Picks the client from the X-Forwarded-For entries. Every proxy appends the address it got
+/// the request from, so the client is the one added by the outermost trusted proxy. Entries
+/// left of it were sent by the client and could be made up. When there are fewer entries,
+/// the request skipped some of the proxies and all the entries are trusted.
+fn forwarded_client<'a>(values: impl Iterator<Item = &'a str>, proxies: usize) -> Option<&'a str> {
+    let entries: Vec<&str> = values
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .collect();
+    entries.get(entries.len().saturating_sub(proxies)).copied()
+}
+
+/// Checks the secret. Clients with failed attempts have to wait increasingly longer
+/// before trying again and only a limited number of checks run at the same time.
+async fn verify_secret(req: &Request<Context>, secret: &str) -> tide::Result<bool> {
+    let ctx = req.state();
+    let client = client_address(req);
+    if let Err(wait) = ctx.throttle.begin_attempt(&client) {
+        warn!("throttled secret attempt from {client}");
+        return Err(tide::Error::from_str(
+            429,
+            format!(
+                "too many failed attempts, try again in {} seconds\n",
+                wait.as_secs() + 1
+            ),
+        ));
+    }
+
+    let crypto = ctx.crypto.clone();
+    let secret = secret.to_owned();
+    let valid = ctx
+        .derivations
+        .run(move || crypto.is_current_secret(&secret))
+        .await
+        .map_err(|_| {
+            tide::Error::from_str(503, "too many secret checks in progress, try again later\n")
+        })?;
+    if valid {
+        ctx.throttle.record_success(&client);
+    } else {
+        warn!("failed secret attempt from {client}");
+    }
+    Ok(valid)
+}
+
+const SESSION_COOKIE: &str = "gimmedat_session";
+
+/// How long an admin stays logged in
+const SESSION_VALIDITY: u64 = 12 * 3600;
+
+/// Returns the valid session of the logged in admin together with its token
+fn current_session(req: &Request<Context>) -> Option<(SessionCapability, String)> {
+    let ctx = req.state();
+    let token = req.cookie(SESSION_COOKIE)?.value().to_owned();
+    let session = ctx
+        .crypto
+        .decrypt(&token)
+        .ok()
+        .and_then(|plain| SessionCapability::from_str(&plain).ok())?;
+    if session.is_expired() || check_not_revoked(ctx, &token).is_err() {
+        return None;
+    }
+    Some((session, token))
+}
+
+fn session_csrf(req: &Request<Context>) -> Option<String> {
+    current_session(req).map(|(session, _)| session.csrf_token().to_owned())
+}
+
+/// Admin forms are authorized either by the secret, or by the session cookie together
+/// with the CSRF token of the session in the form
+async fn authorize_form(req: &Request<Context>, auth: &FormAuth) -> tide::Result<bool> {
+    if !auth.s.is_empty() {
+        return verify_secret(req, &auth.s).await;
+    }
+    Ok(session_form(req, &auth.x))
+}
+
+/// Forms which only the logged in owner can submit
+fn session_form(req: &Request<Context>, csrf: &str) -> bool {
+    match current_session(req) {
+        Some((session, _)) => !csrf.is_empty() && constant_time_eq(session.csrf_token(), csrf),
+        None => false,
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct LoginQuery {
+    // secret
+    s: String,
+}
+
+async fn post_login(mut req: Request<Context>) -> tide::Result {
+    let body: LoginQuery = req.body_form().await?;
+    if !verify_secret(&req, &body.s).await? {
+        return Ok(IndexTemplate::new(true).into());
+    }
+    let ctx = req.state();
+
+    let token = ctx
+        .crypto
+        .encrypt(&SessionCapability::new(SESSION_VALIDITY).to_string());
+    let cookie = Cookie::build(SESSION_COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(ctx.base_url.starts_with("https://"))
+        .finish();
+    let mut res: Response = Redirect::see_other("/").into();
+    res.insert_cookie(cookie);
+    Ok(res)
+}
+
+#[derive(Deserialize, Debug)]
+struct LogoutQuery {
+    // CSRF token of the session
+    x: String,
+}
+
+async fn post_logout(mut req: Request<Context>) -> tide::Result {
+    let body: LogoutQuery = req.body_form().await?;
+    if let Some((session, token)) = current_session(&req) {
+        if !constant_time_eq(session.csrf_token(), &body.x) {
+            return Err(tide::Error::from_str(403, "invalid CSRF token"));
+        }
+        /* the cookie could have been copied, so the session is invalidated on the server too */
+        let id = token_id(&token).map_err(|err| tide::Error::from_str(400, err))?;
+        req.state().revoked.revoke(&id)?;
+    }
+
+    let mut res: Response = Redirect::see_other("/").into();
+    res.remove_cookie(Cookie::build(SESSION_COOKIE, "").path("/").finish());
+    Ok(res)
+}
+
 async fn post_gen(mut req: Request<Context>) -> tide::Result {
     let body: GenQuery = req.body_form().await?;
-    let token = UploadCapability::new(body.n, body.m, body.t);
-    let link = token_to_link(req.state(), &token);
+    let token = UploadCapability::new(body.n, body.m, body.t)
+        .with_file_size_limit(body.f)
+        .with_file_count_limit(body.c)
+        .with_accepted_file_types(body.a)
+        .with_activation_delay(body.b);
+
+    if !authorize_form(&req, &body.auth).await? {
+        return Ok(IndexTemplate::new(true).into());
+    }
+    if let Err(err) = token.validate() {
+        return Ok(IndexTemplate::new(false)
+            .with_session(session_csrf(&req))
+            .with_error(format!("The link was not created, {err}."))
+            .into());
+    }
+
+    Ok(tide::Redirect::new(token_to_link(req.state(), &token)).into())
+}
 
-    let crypt = CryptoState::new(&body.s);
-    let valid_secret = crypt == req.state().crypto;
+async fn post_gen_download(mut req: Request<Context>) -> tide::Result {
+    let body: GenDownloadQuery = req.body_form().await?;
+    if !authorize_form(&req, &body.auth).await? {
+        return Ok(IndexTemplate::new(true).into());
+    }
+
+    let cap = DownloadCapability::new(body.n, body.t);
+    if let Err(err) = cap.validate() {
+        return Ok(IndexTemplate::new(false)
+            .with_session(session_csrf(&req))
+            .with_error(format!("The link was not created, {err}."))
+            .into());
+    }
+    let token = req.state().crypto.encrypt(&cap.to_string());
+    Ok(tide::Redirect::new(req.state().create_download_link(&token)).into())
+}
+
+async fn post_revoke(mut req: Request<Context>) -> tide::Result {
+    let body: RevokeQuery = req.body_form().await?;
+    if !authorize_form(&req, &body.auth).await? {
+        return Ok(IndexTemplate::new(true).into());
+    }
+
+    let msg = match req.state().crypto.find_token(&body.l).map(token_id) {
+        Some(Ok(id)) => match req.state().revoked.revoke(&id)? {
+            true => format!("Link {id} revoked."),
+            false => format!("Link {id} was already revoked."),
+        },
+        _ => "No valid link found, nothing revoked.".to_owned(),
+    };
+    Ok(IndexTemplate::new(false)
+        .with_session(session_csrf(&req))
+        .with_message(msg)
+        .into())
+}
+
+#[derive(Deserialize, Debug)]
+struct AdminQuery {
+    #[serde(flatten)]
+    auth: FormAuth,
+}
 
-    if valid_secret {
-        Ok(tide::Redirect::new(link).into())
+/// How long the links for browsing directories from the dashboard are valid
+const ADMIN_BROWSE_VALIDITY: u64 = 24 * 3600;
+
+async fn admin(req: Request<Context>) -> tide::Result {
+    if current_session(&req).is_none() {
+        return Ok(Redirect::see_other("/").into());
+    }
+    render_dashboard(req.state())
+}
+
+async fn post_admin(mut req: Request<Context>) -> tide::Result {
+    let body: AdminQuery = req.body_form().await?;
+    if !authorize_form(&req, &body.auth).await? {
+        return Ok(IndexTemplate::new(true).into());
+    }
+    render_dashboard(req.state())
+}
+
+fn render_dashboard(ctx: &Context) -> tide::Result {
+    let mut directories = Vec::new();
+    for name in ctx.dirs.list_directory_names()? {
+        let summary = Directory::summarize(&name, &ctx.dirs.path(&name), ctx.dirs.accounting())?;
+        let cap = DownloadCapability::new(name, ADMIN_BROWSE_VALIDITY);
+        let browse_url = ctx.create_download_link(&ctx.crypto.encrypt(&cap.to_string()));
+        directories.push((summary, browse_url));
+    }
+    Ok(AdminTemplate::new(directories).into())
+}
+
+/// Decodes and validates a file name from the URL
+fn decode_file_name(encoded: &str) -> tide::Result<String> {
+    let name = percent_decode_str(encoded).decode_utf8()?.into_owned();
+    validate_file_name(&name).map_err(|err| tide::Error::from_str(400, format!("{err}\n")))?;
+    Ok(name)
+}
+
+/// Loads a directory managed by the owner. Unlike uploads, this never creates it.
+async fn owner_directory(
+    ctx: &Context,
+    encoded_name: &str,
+) -> tide::Result<(String, Arc<Directory>)> {
+    let name = percent_decode_str(encoded_name).decode_utf8()?.into_owned();
+    validate_dir_name(&name).map_err(|err| tide::Error::from_str(400, format!("{err}\n")))?;
+    if !ctx.dirs.path(&name).is_dir() {
+        return Err(tide::Error::from_str(404, "no such directory\n"));
+    }
+    let dir = ctx.dirs.get(&name).await?;
+    Ok((name, dir))
+}
+
+async fn admin_directory(req: Request<Context>) -> tide::Result {
+    let csrf = match session_csrf(&req) {
+        Some(csrf) => csrf,
+        None => return Ok(Redirect::see_other("/").into()),
+    };
+    let (name, dir) = owner_directory(req.state(), req.param("dir")?).await?;
+    let files = dir.list_finished_files()?;
+    Ok(AdminDirectoryTemplate::new(name, csrf, files).into())
+}
+
+#[derive(Deserialize, Debug)]
+struct ManageFileQuery {
+    // CSRF token of the session
+    x: String,
+    /// file name
+    f: String,
+    /// new file name when renaming
+    #[serde(default)]
+    t: String,
+}
+
+async fn admin_delete_file(mut req: Request<Context>) -> tide::Result {
+    let body: ManageFileQuery = req.body_form().await?;
+    if !session_form(&req, &body.x) {
+        return Ok(IndexTemplate::new(true).into());
+    }
+
+    let (name, dir) = owner_directory(req.state(), req.param("dir")?).await?;
+    validate_file_name(&body.f).map_err(|err| tide::Error::from_str(400, format!("{err}\n")))?;
+    dir.delete_file(&body.f).await.map_err(file_error_to_http)?;
+    info!("owner deleted {name}/{}", body.f);
+    Ok(Redirect::see_other(format!("/admin/dir/{}", req.param("dir")?)).into())
+}
+
+async fn admin_rename_file(mut req: Request<Context>) -> tide::Result {
+    let body: ManageFileQuery = req.body_form().await?;
+    if !session_form(&req, &body.x) {
+        return Ok(IndexTemplate::new(true).into());
+    }
+
+    let (name, dir) = owner_directory(req.state(), req.param("dir")?).await?;
+    for file_name in [&body.f, &body.t] {
+        validate_file_name(file_name)
+            .map_err(|err| tide::Error::from_str(400, format!("{err}\n")))?;
+    }
+    dir.rename_file(&body.f, &body.t)
+        .await
+        .map_err(file_error_to_http)?;
+    info!("owner renamed {name}/{} to {}", body.f, body.t);
+    Ok(Redirect::see_other(format!("/admin/dir/{}", req.param("dir")?)).into())
+}
+
+/// Refuses tokens on the revocation list
+fn check_not_revoked(ctx: &Context, token: &str) -> tide::Result<()> {
+    let id = token_id(token).map_err(|err| tide::Error::from_str(401, err))?;
+    if ctx.revoked.is_revoked(&id) {
+        return Err(tide::Error::from_str(403, "link revoked\n"));
+    }
+    Ok(())
+}
+
+async fn index(req: Request<Context>) -> tide::Result {
+    Ok(IndexTemplate::new(false)
+        .with_session(session_csrf(&req))
+        .into())
+}
+
+fn decrypt_capability(ctx: &Context, token: &str) -> tide::Result<UploadCapability> {
+    let tok = ctx
+        .crypto
+        .decrypt(token)
+        .map_err(|err| tide::Error::from_str(401, err))?;
+    check_not_revoked(ctx, token)?;
+    UploadCapability::from_str(&tok).map_err(|err| tide::Error::from_str(400, err))
+}
+
+/// Checks that the capability allows writing data right now
+fn check_capability(cap: &UploadCapability) -> tide::Result<()> {
+    if cap.is_expired() {
+        return Err(tide::Error::from_str(403, "link expired\n"));
+    }
+    if !cap.is_active() {
+        return Err(tide::Error::from_str(
+            403,
+            format!(
+                "link not active yet, uploads open in {} seconds\n",
+                cap.time_until_active_secs()
+            ),
+        ));
+    }
+    if let Err(err) = cap.validate() {
+        return Err(tide::Error::from_str(
+            400,
+            format!("link data invalid: {err}\n"),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Number of bytes at the start of a file its type is detected from
+const SNIFFED_PREFIX_LEN: u64 = 8 * 1024;
+
+/// Rejects files not allowed by the link, judging by the name and the first bytes of the body.
+/// The bytes read are put back in front of the rest of the body.
+async fn check_file_type(
+    cap: &UploadCapability,
+    name: &str,
+    body: &mut tide::Body,
+) -> tide::Result<()> {
+    if cap.accepted_file_types().is_none() {
+        return Ok(());
+    }
+
+    let mut rest = std::mem::replace(body, Body::empty());
+    let len = rest.len();
+    let mut head = Vec::new();
+    (&mut rest)
+        .take(SNIFFED_PREFIX_LEN)
+        .read_to_end(&mut head)
+        .await?;
+    let sniffed = infer::Infer::new().get(&head).map(|t| t.mime);
+    *body = Body::from_reader(async_std::io::Cursor::new(head).chain(rest), len);
+
+    if cap.allows_file_type(name, sniffed.as_deref()) {
+        Ok(())
     } else {
-        Ok(IndexTemplate::new(true).into())
+        Err(tide::Error::from_str(
+            415,
+            "this type of file is not allowed by the link\n",
+        ))
     }
 }
 
-async fn index(_req: Request<Context>) -> tide::Result {
-    Ok(IndexTemplate::new(false).into())
+fn file_error_to_http(err: FileError) -> tide::Error {
+    warn!("Error processing request: {}", err);
+    let status = match err {
+        FileError::AlreadyExists => 409,
+        FileError::NotFound => 404,
+        FileError::Busy => 423,
+        FileError::OffsetMismatch { .. } => 409,
+        FileError::TooManyFiles { .. } => 403,
+        FileError::DataLimitExceeded => 400,
+        FileError::InsufficientStorage => 507,
+        FileError::Io(_) => 500,
+    };
+    tide::Error::from_str(status, format!("{err}\n"))
 }
 
 async fn upload(mut req: Request<Context>) -> tide::Result {
@@ -132,44 +706,58 @@ async fn upload(mut req: Request<Context>) -> tide::Result {
         .header("Content-Length")
         .map(|h| h.as_str().parse::<u64>().ok())
         .unwrap_or(None);
+    let chunked = is_chunked(&req);
     let temp_name = u64::to_string(&OsRng.next_u64());
     let name = req.param("name").unwrap_or(&temp_name);
-    let tok = req
-        .state()
-        .crypto
-        .decrypt(token)
-        .map_err(|err| tide::Error::from_str(401, err))?;
-    let tok = UploadCapability::from_str(&tok).unwrap();
+    let tok = decrypt_capability(req.state(), token)?;
 
-    handle_upload(tok, name, body, content_length, req.state()).await
+    handle_upload(tok, name, body, content_length, chunked, req.state()).await
+}
+
+/// Chunked must be the last transfer coding applied to the body
+fn is_chunked<State>(req: &Request<State>) -> bool {
+    req.header("Transfer-Encoding")
+        .and_then(|values| values.iter().flat_map(|v| v.as_str().split(',')).last())
+        .map(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false)
 }
 
 async fn handle_upload(
     cap: UploadCapability,
     name: &str,
-    body: tide::Body,
+    mut body: tide::Body,
     content_length: Option<u64>,
+    chunked: bool,
     ctx: &Context,
 ) -> tide::Result {
-    if cap.is_expired() {
-        return Err(tide::Error::from_str(403, "link expired\n"));
-    }
-    if let Err(err) = cap.validate() {
-        return Err(tide::Error::from_str(
-            400,
-            format!("link data invalid: {err}\n"),
-        ));
+    check_capability(&cap)?;
+    if let Err(err) = validate_file_name(name) {
+        return Err(tide::Error::from_str(400, format!("{err}\n")));
     }
+    check_file_type(&cap, name, &mut body).await?;
 
     /* get a target directory reference */
     let directory = ctx.dirs.get(cap.dir_name()).await?;
 
+    let remaining_storage = directory.get_remaining_storage();
+    if remaining_storage == 0 || body.len().unwrap_or(0) as u64 > remaining_storage {
+        return Err(file_error_to_http(FileError::InsufficientStorage));
+    }
+
     if body.len().unwrap_or(0) as u64 > directory.get_remaining_bytes(&cap) {
         return Err(tide::Error::from_str(
             400,
             "the data want to upload does not fit within the data limit\n",
         ));
     }
+    if let Some(size) = content_length {
+        if !cap.allows_file_size(size) {
+            return Err(tide::Error::from_str(
+                413,
+                "the file is larger than the per-file size limit\n",
+            ));
+        }
+    }
     if directory.get_remaining_bytes(&cap) == 0 {
         return Err(tide::Error::from_str(
             400,
@@ -177,21 +765,20 @@ async fn handle_upload(
         ));
     }
 
-    let mut file = match directory
+    let mut file = directory
         .create_file_writer(&cap, name, content_length)
         .await
-    {
-        Ok(a) => a,
-        Err(err) => {
-            warn!("Error processing request: {}", err);
-            return Err(tide::Error::from_str(500, err));
-        }
-    };
+        .map_err(file_error_to_http)?;
 
     let mut msgs = vec![];
 
     /* process the uploaded data */
     let res = copy(body, &mut file).await; // we ignore errors, they are handled by the custom writer itself
+
+    /* the chunked decoder returns an error when the connection ends before the terminating chunk */
+    if chunked && res.is_ok() {
+        file.mark_stream_complete();
+    }
     if let Err(e) = res {
         warn!("IO error: {:?}", e);
         if let Some(err) = e.source() {
@@ -201,13 +788,19 @@ async fn handle_upload(
         }
     }
     let bytes_written = file.get_bytes_really_written();
+    _ = file.settle_charge().await;
+    let out_of_storage = file.is_out_of_storage();
 
     // the file writer object handles renames, deallocation of IO objects and everything else
     let m = file.finalize().await;
     msgs.extend(m);
 
     /* return message that will be displayed to curl users */
-    Ok(UploadResponseTemplate::new(bytes_written, msgs).into())
+    let mut res: Response = UploadResponseTemplate::new(bytes_written, msgs).into();
+    if out_of_storage {
+        res.set_status(507);
+    }
+    Ok(res)
 }
 
 async fn upload_public(mut req: Request<Context>) -> tide::Result {
@@ -216,11 +809,12 @@ async fn upload_public(mut req: Request<Context>) -> tide::Result {
         .header("Content-Length")
         .map(|h| h.as_str().parse::<u64>().ok())
         .unwrap_or(None);
+    let chunked = is_chunked(&req);
     let temp_name = u64::to_string(&OsRng.next_u64());
     let name = req.param("name").unwrap_or(&temp_name);
     let cap = req.state().create_public_capability();
 
-    handle_upload(cap, name, body, content_length, req.state()).await
+    handle_upload(cap, name, body, content_length, chunked, req.state()).await
 }
 
 async fn upload_help(req: Request<Context>) -> tide::Result {
@@ -230,6 +824,7 @@ async fn upload_help(req: Request<Context>) -> tide::Result {
         .crypto
         .decrypt(token)
         .map_err(|err| tide::Error::from_str(400, err))?;
+    check_not_revoked(req.state(), token)?;
     let cap = UploadCapability::from_str(&query);
 
     let url = req.state().create_link(token);
@@ -254,5 +849,876 @@ async fn upload_help_public(req: Request<Context>) -> tide::Result {
         req.state().dirs.get(cap.dir_name()).await?,
     )
     .await
+    .without_resumable_uploads()
     .into())
 }
+
+/* Resumable uploads implementing the core of the tus 1.0 protocol (https://tus.io/protocols/resumable-upload)
+with the creation and termination extensions. The upload URL contains an encrypted ResumableUpload
+and the current offset is always the size of the partial file on disk. */
+
+const TUS_VERSION: &str = "1.0.0";
+
+fn tus_response(status: u16) -> tide::Response {
+    let mut res = tide::Response::new(status);
+    res.insert_header("Tus-Resumable", TUS_VERSION);
+    res
+}
+
+fn check_tus_version<State>(req: &Request<State>) -> tide::Result<()> {
+    match req.header("Tus-Resumable") {
+        Some(v) if v.as_str() == TUS_VERSION => Ok(()),
+        _ => Err(tide::Error::from_str(
+            412,
+            format!("unsupported tus version, only {TUS_VERSION} is supported\n"),
+        )),
+    }
+}
+
+fn parse_header_u64<State>(req: &Request<State>, name: &str) -> tide::Result<u64> {
+    req.header(name)
+        .and_then(|h| h.as_str().parse::<u64>().ok())
+        .ok_or_else(|| tide::Error::from_str(400, format!("missing or invalid {name} header\n")))
+}
+
+/// Extracts the file name from the Upload-Metadata header, which has the form `key base64value,key2 base64value2`
+fn parse_tus_file_name(metadata: &str) -> Option<String> {
+    metadata.split(',').find_map(|pair| {
+        let mut parts = pair.trim().splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some("filename"), Some(value)) => STANDARD
+                .decode(value)
+                .ok()
+                .and_then(|v| String::from_utf8(v).ok()),
+            _ => None,
+        }
+    })
+}
+
+/// Decrypts the upload ID, other links than the one it was created with don't know it
+fn decrypt_resumable_upload(
+    ctx: &Context,
+    id: &str,
+    token: &str,
+    cap: &UploadCapability,
+) -> tide::Result<ResumableUpload> {
+    let id = ctx
+        .crypto
+        .decrypt(id)
+        .map_err(|err| tide::Error::from_str(404, err))?;
+    let upload = ResumableUpload::from_str(&id).map_err(|err| tide::Error::from_str(404, err))?;
+    let token_id = token_id(token).map_err(|err| tide::Error::from_str(401, err))?;
+    if !upload.belongs_to(cap.dir_name(), &token_id) {
+        return Err(tide::Error::from_str(404, "upload not found\n"));
+    }
+    Ok(upload)
+}
+
+async fn tus_options(_req: Request<Context>) -> tide::Result {
+    let mut res = tus_response(204);
+    res.insert_header("Tus-Version", TUS_VERSION);
+    res.insert_header("Tus-Extension", "creation,termination");
+    Ok(res)
+}
+
+async fn tus_create(req: Request<Context>) -> tide::Result {
+    check_tus_version(&req)?;
+    let token = req.param("token")?;
+    let cap = decrypt_capability(req.state(), token)?;
+    check_capability(&cap)?;
+
+    let length = parse_header_u64(&req, "Upload-Length")?;
+    let name = req
+        .header("Upload-Metadata")
+        .and_then(|h| parse_tus_file_name(h.as_str()))
+        .unwrap_or_else(|| u64::to_string(&OsRng.next_u64()));
+    if let Err(err) = validate_file_name(&name) {
+        return Err(tide::Error::from_str(400, format!("{err}\n")));
+    }
+    if !cap.allows_file_name(&name) {
+        return Err(tide::Error::from_str(
+            415,
+            "this type of file is not allowed by the link\n",
+        ));
+    }
+
+    let directory = req.state().dirs.get(cap.dir_name()).await?;
+    if !cap.allows_file_size(length) {
+        return Err(tide::Error::from_str(
+            413,
+            "the file is larger than the per-file size limit\n",
+        ));
+    }
+    if length > directory.get_remaining_storage() {
+        return Err(file_error_to_http(FileError::InsufficientStorage));
+    }
+    if length > directory.get_remaining_bytes(&cap) {
+        return Err(tide::Error::from_str(
+            413,
+            "the data want to upload does not fit within the data limit\n",
+        ));
+    }
+
+    directory
+        .create_resumable_file(&cap, &name)
+        .await
+        .map_err(file_error_to_http)?;
+
+    /* empty uploads are complete right away */
+    if length == 0 {
+        let file = directory
+            .resume_file_writer(&cap, &name, 0, 0)
+            .await
+            .map_err(file_error_to_http)?;
+        file.finalize().await;
+    }
+
+    let upload = ResumableUpload::new(
+        cap.dir_name().to_owned(),
+        token_id(token).map_err(|err| tide::Error::from_str(401, err))?,
+        name,
+        length,
+    );
+    let location = format!(
+        "{}tus/{}",
+        req.state().create_link(token),
+        req.state().crypto.encrypt(&upload.to_string())
+    );
+
+    let mut res = tus_response(201);
+    res.insert_header("Location", location);
+    Ok(res)
+}
+
+async fn tus_head(req: Request<Context>) -> tide::Result {
+    check_tus_version(&req)?;
+    let token = req.param("token")?;
+    let cap = decrypt_capability(req.state(), token)?;
+    check_capability(&cap)?;
+    let upload = decrypt_resumable_upload(req.state(), req.param("id")?, token, &cap)?;
+    let directory = req.state().dirs.get(cap.dir_name()).await?;
+
+    let offset = match directory.get_resumable_offset(upload.file_name()).await {
+        Ok(offset) => offset,
+        Err(FileError::AlreadyExists) => upload.length(),
+        Err(err) => return Err(file_error_to_http(err)),
+    };
+
+    let mut res = tus_response(200);
+    res.insert_header("Upload-Offset", offset.to_string());
+    res.insert_header("Upload-Length", upload.length().to_string());
+    res.insert_header("Cache-Control", "no-store");
+    Ok(res)
+}
+
+async fn tus_patch(mut req: Request<Context>) -> tide::Result {
+    check_tus_version(&req)?;
+    if req.content_type().map(|m| m.essence().to_owned())
+        != Some("application/offset+octet-stream".to_owned())
+    {
+        return Err(tide::Error::from_str(
+            415,
+            "expected Content-Type application/offset+octet-stream\n",
+        ));
+    }
+    let offset = parse_header_u64(&req, "Upload-Offset")?;
+    let mut body = req.take_body();
+    let token = req.param("token")?;
+    let cap = decrypt_capability(req.state(), token)?;
+    check_capability(&cap)?;
+    let upload = decrypt_resumable_upload(req.state(), req.param("id")?, token, &cap)?;
+    if offset == 0 {
+        check_file_type(&cap, upload.file_name(), &mut body).await?;
+    }
+    let directory = req.state().dirs.get(cap.dir_name()).await?;
+
+    let mut file = directory
+        .resume_file_writer(&cap, upload.file_name(), offset, upload.length())
+        .await
+        .map_err(file_error_to_http)?;
+
+    /* whatever was written before an error is kept and can be continued from */
+    let res = copy(body, &mut file).await;
+    let settled = file.settle_charge().await;
+    let new_offset = file.get_current_offset();
+    let out_of_storage = file.is_out_of_storage();
+    file.finalize().await;
+
+    if let Err(e) = res {
+        warn!("IO error during resumable upload: {:?}", e);
+        return Err(tide::Error::from_str(
+            if out_of_storage { 507 } else { 500 },
+            format!("IO error while transferring the file: {e}\n"),
+        ));
+    }
+    /* the data were received, but they take more space than the limits allow and were given up */
+    settled.map_err(file_error_to_http)?;
+
+    let mut res = tus_response(204);
+    res.insert_header("Upload-Offset", new_offset.to_string());
+    Ok(res)
+}
+
+async fn tus_delete(req: Request<Context>) -> tide::Result {
+    check_tus_version(&req)?;
+    let token = req.param("token")?;
+    let cap = decrypt_capability(req.state(), token)?;
+    check_capability(&cap)?;
+    let upload = decrypt_resumable_upload(req.state(), req.param("id")?, token, &cap)?;
+    let directory = req.state().dirs.get(cap.dir_name()).await?;
+
+    directory
+        .terminate_resumable_file(upload.file_name())
+        .await
+        .map_err(file_error_to_http)?;
+
+    Ok(tus_response(204))
+}
+
+fn decrypt_download_capability(ctx: &Context, token: &str) -> tide::Result<DownloadCapability> {
+    let tok = ctx
+        .crypto
+        .decrypt(token)
+        .map_err(|err| tide::Error::from_str(401, err))?;
+    check_not_revoked(ctx, token)?;
+    let cap = DownloadCapability::from_str(&tok).map_err(|err| tide::Error::from_str(400, err))?;
+
+    if cap.is_expired() {
+        return Err(tide::Error::from_str(403, "link expired\n"));
+    }
+    if let Err(err) = cap.validate() {
+        return Err(tide::Error::from_str(
+            400,
+            format!("link data invalid: {err}\n"),
+        ));
+    }
+
+    Ok(cap)
+}
+
+/// Loads the directory of a download link, see `owner_directory`
+async fn download_directory(
+    ctx: &Context,
+    cap: &DownloadCapability,
+) -> tide::Result<Arc<Directory>> {
+    if !ctx.dirs.path(cap.dir_name()).is_dir() {
+        return Err(tide::Error::from_str(404, "no such directory\n"));
+    }
+    Ok(ctx.dirs.get(cap.dir_name()).await?)
+}
+
+async fn download_listing(req: Request<Context>) -> tide::Result {
+    let token = req.param("token")?;
+    let cap = decrypt_download_capability(req.state(), token)?;
+    let directory = download_directory(req.state(), &cap).await?;
+
+    let url = req.state().create_download_link(token);
+    let zip_url = req.state().create_zip_link(token);
+    Ok(DownloadTemplate::new(&url, &zip_url, &cap, directory.list_finished_files()?).into())
+}
+
+async fn download_zip(req: Request<Context>) -> tide::Result {
+    let cap = decrypt_download_capability(req.state(), req.param("token")?)?;
+    let directory = download_directory(req.state(), &cap).await?;
+
+    let files = directory
+        .list_finished_files()?
+        .into_iter()
+        .filter_map(|(name, _)| match name.into_string() {
+            Ok(name) => Some((directory.get_final_file_name(&name), name)),
+            Err(name) => {
+                warn!(
+                    "skipping file with non-UTF8 name {:?} in ZIP download",
+                    name
+                );
+                None
+            }
+        })
+        .collect();
+    let zip = ZipStream::new(files)?;
+    let size = zip.total_size();
+    info!(
+        "streaming ZIP of directory \"{}\" ({size} bytes)",
+        cap.dir_name()
+    );
+
+    let mut body = tide::Body::from_reader(BufReader::new(zip), Some(size as usize));
+    body.set_mime("application/zip");
+    let mut res = tide::Response::new(200);
+    res.set_body(body);
+    res.insert_header(
+        "Content-Disposition",
+        format!(
+            "attachment; filename*=UTF-8''{}.zip",
+            utf8_percent_encode(cap.dir_name(), NON_ALPHANUMERIC)
+        ),
+    );
+
+    Ok(res)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// no usable Range header, send everything
+    Full,
+    /// inclusive start and end
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses the Range header. Only a single range is supported, anything else is served whole.
+fn parse_range(header: Option<&str>, size: u64) -> ByteRange {
+    let spec = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return ByteRange::Full,
+    };
+
+    match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+        /* suffix range with the number of final bytes */
+        (None, Some(suffix)) if start.is_empty() => {
+            if suffix == 0 || size == 0 {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial(size - suffix.min(size), size - 1)
+            }
+        }
+        (Some(start), None) if end.is_empty() => {
+            if start < size {
+                ByteRange::Partial(start, size - 1)
+            } else {
+                ByteRange::Unsatisfiable
+            }
+        }
+        (Some(start), Some(end)) if start <= end => {
+            if start < size {
+                ByteRange::Partial(start, end.min(size - 1))
+            } else {
+                ByteRange::Unsatisfiable
+            }
+        }
+        _ => ByteRange::Full,
+    }
+}
+
+async fn download_file(req: Request<Context>) -> tide::Result {
+    let cap = decrypt_download_capability(req.state(), req.param("token")?)?;
+    let name = percent_decode_str(req.param("name")?).decode_utf8()?;
+    if let Err(err) = validate_file_name(&name) {
+        return Err(tide::Error::from_str(400, format!("{err}\n")));
+    }
+
+    let directory = download_directory(req.state(), &cap).await?;
+    let (mut file, size) = directory
+        .open_finished_file(&name)
+        .await
+        .map_err(file_error_to_http)?;
+
+    let mut res = tide::Response::new(200);
+    res.insert_header("Accept-Ranges", "bytes");
+    let (start, length) = match parse_range(req.header("Range").map(|h| h.as_str()), size) {
+        ByteRange::Full => (0, size),
+        ByteRange::Partial(start, end) => {
+            res.set_status(206);
+            res.insert_header("Content-Range", format!("bytes {start}-{end}/{size}"));
+            (start, end - start + 1)
+        }
+        ByteRange::Unsatisfiable => {
+            let mut res = tide::Response::new(416);
+            res.insert_header("Content-Range", format!("bytes */{size}"));
+            return Ok(res);
+        }
+    };
+
+    file.seek(SeekFrom::Start(start)).await?;
+    let mut body =
+        tide::Body::from_reader(BufReader::new(file.take(length)), Some(length as usize));
+    let mime = mime_guess::from_path(name.as_ref()).first_or_octet_stream();
+    body.set_mime(Mime::from_str(mime.essence_str()).unwrap_or(tide::http::mime::BYTE_STREAM));
+    res.set_body(body);
+    res.insert_header(
+        "Content-Disposition",
+        format!(
+            "attachment; filename*=UTF-8''{}",
+            utf8_percent_encode(&name, NON_ALPHANUMERIC)
+        ),
+    );
+
+    Ok(res)
+}
+
+/* JSON admin API, authenticated by the secret or an API key in the Authorization header */
+
+/// The routes are registered directly, a nested server would lose to the dynamic GET routes
+fn register_api(app: &mut tide::Server<Context>) {
+    let mut api = app.at("/api");
+    api.with(After(|mut res: Response| async {
+        if let Some(err) = res.take_error() {
+            res.set_body(Body::from_json(
+                &serde_json::json!({ "error": err.to_string() }),
+            )?);
+        }
+        Ok(res)
+    }));
+    api.with(api_auth);
+    api.at("/links").post(api_create_link);
+    api.at("/download-links").post(api_create_download_link);
+    api.at("/directories").get(api_list_directories);
+    api.at("/directories/:dir/files").get(api_list_files);
+    api.at("/directories/:dir/files/:name")
+        .delete(api_delete_file)
+        .patch(api_rename_file);
+    api.at("/revoke").post(api_revoke);
+}
+
+fn api_auth<'a>(
+    req: Request<Context>,
+    next: Next<'a, Context>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+    Box::pin(async move {
+        let bearer = req
+            .header("Authorization")
+            .and_then(|h| h.last().as_str().strip_prefix("Bearer "))
+            .map(str::trim)
+            .unwrap_or_default();
+        if !api_credentials_valid(&req, bearer).await? {
+            let mut res = Response::new(401);
+            res.insert_header("WWW-Authenticate", "Bearer");
+            res.set_error(tide::Error::from_str(401, "invalid credentials"));
+            return Ok(res);
+        }
+        Ok(next.run(req).await)
+    })
+}
+
+async fn api_credentials_valid(req: &Request<Context>, bearer: &str) -> tide::Result<bool> {
+    if bearer.is_empty() {
+        return Ok(false);
+    }
+
+    let ctx = req.state();
+    let api_key = ctx
+        .crypto
+        .decrypt(bearer)
+        .ok()
+        .and_then(|plain| ApiKeyCapability::from_str(&plain).ok());
+    match api_key {
+        Some(key) => {
+            let revoked = check_not_revoked(ctx, bearer).is_err();
+            if revoked || key.is_expired() {
+                warn!("revoked or expired API key {:?} used", key.label());
+                return Ok(false);
+            }
+            info!("API access with key {:?}", key.label());
+            Ok(true)
+        }
+        None => verify_secret(req, bearer).await,
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiLinkRequest {
+    name: String,
+    size: u64,
+    valid_for: u64,
+    max_file_size: Option<u64>,
+    max_files: Option<u64>,
+    accept: Option<String>,
+    opens_in: Option<u64>,
+}
+
+async fn api_create_link(mut req: Request<Context>) -> tide::Result {
+    let body: ApiLinkRequest = req.body_json().await?;
+    let cap = UploadCapability::new(body.name, body.size, body.valid_for)
+        .with_file_size_limit(body.max_file_size)
+        .with_file_count_limit(body.max_files)
+        .with_accepted_file_types(body.accept)
+        .with_activation_delay(body.opens_in);
+    cap.validate()
+        .map_err(|err| tide::Error::from_str(400, err))?;
+
+    let token = req.state().crypto.encrypt(&cap.to_string());
+    let info = UploadLinkInfo::new(req.state().create_link(&token), &token, &cap);
+    Ok(Response::builder(201).body(Body::from_json(&info)?).build())
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiDownloadLinkRequest {
+    name: String,
+    valid_for: u64,
+}
+
+async fn api_create_download_link(mut req: Request<Context>) -> tide::Result {
+    let body: ApiDownloadLinkRequest = req.body_json().await?;
+    let cap = DownloadCapability::new(body.name, body.valid_for);
+    cap.validate()
+        .map_err(|err| tide::Error::from_str(400, err))?;
+
+    let ctx = req.state();
+    let token = ctx.crypto.encrypt(&cap.to_string());
+    let info = serde_json::json!({
+        "link": ctx.create_download_link(&token),
+        "zip_link": ctx.create_zip_link(&token),
+        "token_id": token_id(&token).map_err(|err| tide::Error::from_str(500, err))?,
+        "directory": cap.dir_name(),
+        "expires_at": cap.expiration_timestamp(),
+    });
+    Ok(Response::builder(201).body(Body::from_json(&info)?).build())
+}
+
+#[derive(Serialize, Debug)]
+struct ApiDirectory {
+    name: String,
+    used_bytes: u64,
+    files: usize,
+}
+
+async fn api_list_directories(req: Request<Context>) -> tide::Result {
+    let mut dirs = Vec::new();
+    for name in req.state().dirs.list_directory_names()? {
+        dirs.push(ApiDirectory {
+            used_bytes: req.state().dirs.get_usage(&name).await?,
+            files: Directory::list_finished_files_in(&req.state().dirs.path(&name))?.len(),
+            name,
+        });
+    }
+    Ok(Body::from_json(&dirs)?.into())
+}
+
+#[derive(Serialize, Debug)]
+struct ApiFile {
+    name: String,
+    size: u64,
+}
+
+async fn api_list_files(req: Request<Context>) -> tide::Result {
+    let (_, dir) = owner_directory(req.state(), req.param("dir")?).await?;
+    let files: Vec<ApiFile> = dir
+        .list_finished_files()?
+        .into_iter()
+        .filter_map(|(name, size)| {
+            Some(ApiFile {
+                name: name.into_string().ok()?,
+                size,
+            })
+        })
+        .collect();
+    Ok(Body::from_json(&files)?.into())
+}
+
+async fn api_delete_file(req: Request<Context>) -> tide::Result {
+    let (name, dir) = owner_directory(req.state(), req.param("dir")?).await?;
+    let file_name = decode_file_name(req.param("name")?)?;
+    dir.delete_file(&file_name)
+        .await
+        .map_err(file_error_to_http)?;
+    info!("owner deleted {name}/{file_name}");
+    Ok(Response::new(204))
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiRenameRequest {
+    /// new file name
+    name: String,
+}
+
+async fn api_rename_file(mut req: Request<Context>) -> tide::Result {
+    let body: ApiRenameRequest = req.body_json().await?;
+    let (name, dir) = owner_directory(req.state(), req.param("dir")?).await?;
+    let file_name = decode_file_name(req.param("name")?)?;
+    validate_file_name(&body.name).map_err(|err| tide::Error::from_str(400, format!("{err}\n")))?;
+    dir.rename_file(&file_name, &body.name)
+        .await
+        .map_err(file_error_to_http)?;
+    info!("owner renamed {name}/{file_name} to {}", body.name);
+    Ok(Body::from_json(&serde_json::json!({ "name": body.name }))?.into())
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiRevokeRequest {
+    /// link or token to revoke
+    link: String,
+}
+
+async fn api_revoke(mut req: Request<Context>) -> tide::Result {
+    let body: ApiRevokeRequest = req.body_json().await?;
+    let ctx = req.state();
+    let id = ctx
+        .crypto
+        .find_token(&body.link)
+        .map(token_id)
+        .and_then(Result::ok)
+        .ok_or_else(|| tide::Error::from_str(400, "no valid link found"))?;
+    let newly_revoked = ctx.revoked.revoke(&id)?;
+    Ok(
+        Body::from_json(&serde_json::json!({ "token_id": id, "newly_revoked": newly_revoked }))?
+            .into(),
+    )
+}
+
+#[test]
+fn test_forwarded_client() {
+    let header = ["1.1.1.1, 2.2.2.2", "3.3.3.3"];
+    assert_eq!(forwarded_client(header.into_iter(), 1), Some("3.3.3.3"));
+    assert_eq!(forwarded_client(header.into_iter(), 2), Some("2.2.2.2"));
+    assert_eq!(forwarded_client(header.into_iter(), 3), Some("1.1.1.1"));
+    assert_eq!(forwarded_client(header.into_iter(), 4), Some("1.1.1.1"));
+    assert_eq!(forwarded_client([].into_iter(), 1), None);
+    assert_eq!(
+        forwarded_client(["[::1]:80"].into_iter(), 1),
+        Some("[::1]:80")
+    );
+}
+
+#[test]
+fn test_parse_range() {
+    assert_eq!(parse_range(None, 100), ByteRange::Full);
+    assert_eq!(
+        parse_range(Some("bytes=0-9"), 100),
+        ByteRange::Partial(0, 9)
+    );
+    assert_eq!(
+        parse_range(Some("bytes=90-"), 100),
+        ByteRange::Partial(90, 99)
+    );
+    assert_eq!(
+        parse_range(Some("bytes=-10"), 100),
+        ByteRange::Partial(90, 99)
+    );
+    assert_eq!(
+        parse_range(Some("bytes=-1000"), 100),
+        ByteRange::Partial(0, 99)
+    );
+    assert_eq!(
+        parse_range(Some("bytes=50-1000"), 100),
+        ByteRange::Partial(50, 99)
+    );
+    assert_eq!(
+        parse_range(Some("bytes=100-"), 100),
+        ByteRange::Unsatisfiable
+    );
+    assert_eq!(parse_range(Some("bytes=0-1,5-6"), 100), ByteRange::Full);
+    assert_eq!(parse_range(Some("items=0-1"), 100), ByteRange::Full);
+}
+
+/// Server for the handler tests with its own data root, which is removed when dropped.
+/// The tests share one watcher.
+#[cfg(test)]
+fn test_app(name: &str) -> (tide::Server<Context>, crate::data::TestDir) {
+    static WATCHER: std::sync::OnceLock<Option<crate::watcher::DirectoryWatcher>> =
+        std::sync::OnceLock::new();
+    let watcher = WATCHER.get_or_init(|| crate::watcher::DirectoryWatcher::start().ok());
+
+    let root = crate::data::TestDir::new(&format!("web-{name}"));
+    let dirs = DirectoryRegistry::with_watcher(
+        root.to_path_buf(),
+        crate::data::Accounting::Apparent,
+        StorageQuota::default(),
+        watcher.clone(),
+    );
+    let app = build_app(Context::new(
+        CryptoState::new("secret", crate::crypto::LEGACY_SALT),
+        "http://localhost".to_owned(),
+        None,
+        dirs,
+    ));
+    (app, root)
+}
+
+#[cfg(test)]
+fn test_request(method: tide::http::Method, path: &str) -> tide::http::Request {
+    tide::http::Request::new(method, format!("http://localhost{path}").as_str())
+}
+
+#[cfg(test)]
+async fn test_tus_patch(
+    app: &tide::Server<Context>,
+    location: &str,
+    offset: u64,
+    data: &'static str,
+) -> tide::http::Response {
+    let mut req = test_request(tide::http::Method::Patch, location);
+    req.set_body(data);
+    req.insert_header("Tus-Resumable", TUS_VERSION);
+    req.insert_header("Upload-Offset", offset.to_string());
+    req.insert_header("Content-Type", "application/offset+octet-stream");
+    app.respond(req).await.unwrap()
+}
+
+#[test]
+fn test_tus_upload() {
+    use tide::http::Method;
+
+    let (app, root) = test_app("tus");
+    let cap = UploadCapability::new("tus-test".to_owned(), 1 << 20, 3600);
+    let token = app.state().crypto.encrypt(&cap.to_string());
+
+    async_std::task::block_on(async {
+        let create = || {
+            let mut req = test_request(Method::Post, &format!("/{token}/tus/"));
+            req.insert_header("Tus-Resumable", TUS_VERSION);
+            req.insert_header("Upload-Length", "10");
+            req.insert_header(
+                "Upload-Metadata",
+                format!("filename {}", STANDARD.encode("a b.txt")),
+            );
+            req
+        };
+        let res: tide::http::Response = app.respond(create()).await.unwrap();
+        assert_eq!(res.status(), 201);
+        let location = res.header("Location").unwrap().as_str();
+        let location = location.strip_prefix("http://localhost").unwrap();
+
+        let head = || async {
+            let mut req = test_request(Method::Head, location);
+            req.insert_header("Tus-Resumable", TUS_VERSION);
+            let res: tide::http::Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.header("Upload-Length").unwrap().as_str(), "10");
+            res.header("Upload-Offset").unwrap().as_str().to_owned()
+        };
+        assert_eq!(head().await, "0");
+
+        let res = test_tus_patch(&app, location, 0, "01234").await;
+        assert_eq!(res.status(), 204);
+        assert_eq!(res.header("Upload-Offset").unwrap().as_str(), "5");
+
+        /* the client disagrees with the server on the offset */
+        let res = test_tus_patch(&app, location, 0, "abcde").await;
+        assert_eq!(res.status(), 409);
+        assert_eq!(head().await, "5");
+
+        /* the name is claimed by the unfinished upload */
+        let res: tide::http::Response = app.respond(create()).await.unwrap();
+        assert_eq!(res.status(), 409);
+
+        let res = test_tus_patch(&app, location, 5, "56789").await;
+        assert_eq!(res.status(), 204);
+        assert_eq!(res.header("Upload-Offset").unwrap().as_str(), "10");
+        assert_eq!(head().await, "10");
+        assert_eq!(
+            std::fs::read_to_string(root.join("tus-test/a b.txt")).unwrap(),
+            "0123456789"
+        );
+
+        let res = test_tus_patch(&app, location, 10, "x").await;
+        assert_eq!(res.status(), 409);
+
+        /* the upload ID doesn't work with another link */
+        let other = app.state().crypto.encrypt(&cap.to_string());
+        let (_, id) = location.rsplit_once('/').unwrap();
+        let res = test_tus_patch(&app, &format!("/{other}/tus/{id}"), 10, "x").await;
+        assert_eq!(res.status(), 404);
+    });
+}
+
+/// Body of a request whose connection was closed in the middle of the chunked encoding
+#[cfg(test)]
+struct TruncatedBody;
+
+#[cfg(test)]
+impl async_std::io::Read for TruncatedBody {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        _buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::task::Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()))
+    }
+}
+
+#[test]
+fn test_chunked_uploads() {
+    use async_std::io::Cursor;
+
+    let (app, root) = test_app("chunked");
+    let cap = UploadCapability::new("chunked-test".to_owned(), 1 << 20, 3600);
+    let token = app.state().crypto.encrypt(&cap.to_string());
+
+    async_std::task::block_on(async {
+        let upload = |name: &str, body: Body| {
+            let mut req = test_request(tide::http::Method::Put, &format!("/{token}/{name}"));
+            req.insert_header("Transfer-Encoding", "gzip, Chunked");
+            req.set_body(body);
+            app.respond(req)
+        };
+
+        let body = Body::from_reader(Cursor::new("0123456789"), None);
+        let res: tide::http::Response = upload("complete.txt", body).await.unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            std::fs::read_to_string(root.join("chunked-test/complete.txt")).unwrap(),
+            "0123456789"
+        );
+
+        let body = Cursor::new("01234").chain(TruncatedBody);
+        let body = Body::from_reader(BufReader::new(body), None);
+        let _: tide::http::Response = upload("truncated.txt", body).await.unwrap();
+        assert!(!root.join("chunked-test/truncated.txt").exists());
+    });
+}
+
+#[test]
+fn test_generated_links_are_validated() {
+    let (app, _root) = test_app("gen");
+
+    async_std::task::block_on(async {
+        for (form, valid) in [
+            ("n=photos&m=100&t=100", true),
+            ("n=.hidden&m=100&t=100", false),
+            ("n=photos&m=100&t=100&a=images", false),
+        ] {
+            let mut req = test_request(tide::http::Method::Post, "/gen");
+            req.set_body(format!("{form}&s=secret"));
+            req.insert_header("Content-Type", "application/x-www-form-urlencoded");
+            let mut res: tide::http::Response = app.respond(req).await.unwrap();
+            let body = res.body_string().await.unwrap();
+            if valid {
+                assert_eq!(res.status(), 302);
+            } else {
+                assert_eq!(res.status(), 200);
+                assert!(body.contains("The link was not created"), "{form}");
+            }
+        }
+    });
+}
+
+#[test]
+fn test_downloads_do_not_create_directories() {
+    let (app, root) = test_app("download");
+    let cap = DownloadCapability::new("download-test".to_owned(), 3600);
+    let token = app.state().crypto.encrypt(&cap.to_string());
+
+    async_std::task::block_on(async {
+        for path in [
+            format!("/dl/{token}/"),
+            format!("/dl/{token}/a.txt"),
+            format!("/zip/{token}"),
+        ] {
+            let res: tide::http::Response = app
+                .respond(test_request(tide::http::Method::Get, &path))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), 404, "{path}");
+        }
+    });
+    assert!(!root.join("download-test").exists());
+}
+
+#[test]
+fn test_api_accepts_any_validity() {
+    let (app, _root) = test_app("api");
+
+    async_std::task::block_on(async {
+        let mut req = test_request(tide::http::Method::Post, "/api/links");
+        req.set_body(serde_json::json!({ "name": "forever", "size": 100, "valid_for": u64::MAX }));
+        req.insert_header("Authorization", "Bearer secret");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), 201);
+        let info: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(info["expires_at"], u64::MAX);
+    });
+}
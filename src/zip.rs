@@ -0,0 +1,512 @@
+use async_std::fs::File;
+use async_std::io::Read;
+use crc32fast::Hasher;
+
+use crate::cli::civil_from_days;
+
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::UNIX_EPOCH;
+
+/* Minimal streaming ZIP writer. Files are stored without compression and the CRC is sent
+in a data descriptor after each file, so nothing has to be staged on disk. As the sizes
+of all files are known in advance, the length of the whole archive is known as well.
+ZIP64 structures are used only when the sizes or offsets require them. */
+
+const ZIP64_LIMIT: u64 = 0xFFFF_FFFF;
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// bit 3: sizes and CRC in a data descriptor, bit 11: UTF-8 file names
+const FLAGS: u16 = 0x0808;
+
+struct ZipEntry {
+    path: PathBuf,
+    name: String,
+    size: u64,
+    /// DOS time and date
+    time: (u16, u16),
+    local_header_offset: u64,
+    /// sizes and offsets from this value up are stored in the ZIP64 fields
+    zip64_limit: u64,
+}
+
+impl ZipEntry {
+    fn zip64(&self) -> bool {
+        self.size >= self.zip64_limit
+    }
+
+    fn zip64_offset(&self) -> bool {
+        self.local_header_offset >= self.zip64_limit
+    }
+
+    fn local_header_len(&self) -> u64 {
+        30 + self.name.len() as u64 + if self.zip64() { 20 } else { 0 }
+    }
+
+    fn data_descriptor_len(&self) -> u64 {
+        if self.zip64() {
+            24
+        } else {
+            16
+        }
+    }
+
+    fn central_extra_len(&self) -> u64 {
+        let mut len = 0;
+        if self.zip64() {
+            len += 16;
+        }
+        if self.zip64_offset() {
+            len += 8;
+        }
+        if len > 0 {
+            len + 4
+        } else {
+            0
+        }
+    }
+
+    fn central_header_len(&self) -> u64 {
+        46 + self.name.len() as u64 + self.central_extra_len()
+    }
+
+    fn version(&self) -> u16 {
+        if self.zip64() || self.zip64_offset() {
+            VERSION_ZIP64
+        } else {
+            VERSION_DEFAULT
+        }
+    }
+
+    fn write_local_header(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        buf.extend_from_slice(&self.version().to_le_bytes());
+        buf.extend_from_slice(&FLAGS.to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes()); // stored
+        buf.extend_from_slice(&self.time.0.to_le_bytes());
+        buf.extend_from_slice(&self.time.1.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes()); // CRC is in the data descriptor
+        if self.zip64() {
+            buf.extend_from_slice(&u32::MAX.to_le_bytes());
+            buf.extend_from_slice(&u32::MAX.to_le_bytes());
+        } else {
+            buf.extend_from_slice(&0u32.to_le_bytes());
+            buf.extend_from_slice(&0u32.to_le_bytes());
+        }
+        buf.extend_from_slice(&(self.name.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&(if self.zip64() { 20u16 } else { 0 }).to_le_bytes());
+        buf.extend_from_slice(self.name.as_bytes());
+        if self.zip64() {
+            /* the sizes follow in the data descriptor, the extra field only marks the entry as ZIP64 */
+            buf.extend_from_slice(&1u16.to_le_bytes());
+            buf.extend_from_slice(&16u16.to_le_bytes());
+            buf.extend_from_slice(&0u64.to_le_bytes());
+            buf.extend_from_slice(&0u64.to_le_bytes());
+        }
+    }
+
+    fn write_data_descriptor(&self, buf: &mut Vec<u8>, crc: u32) {
+        buf.extend_from_slice(&0x08074b50u32.to_le_bytes());
+        buf.extend_from_slice(&crc.to_le_bytes());
+        if self.zip64() {
+            buf.extend_from_slice(&self.size.to_le_bytes());
+            buf.extend_from_slice(&self.size.to_le_bytes());
+        } else {
+            buf.extend_from_slice(&(self.size as u32).to_le_bytes());
+            buf.extend_from_slice(&(self.size as u32).to_le_bytes());
+        }
+    }
+
+    fn write_central_header(&self, buf: &mut Vec<u8>, crc: u32) {
+        let size = if self.zip64() {
+            u32::MAX
+        } else {
+            self.size as u32
+        };
+        let offset = if self.zip64_offset() {
+            u32::MAX
+        } else {
+            self.local_header_offset as u32
+        };
+
+        buf.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        buf.extend_from_slice(&(0x0300 | VERSION_ZIP64).to_le_bytes()); // made by unix
+        buf.extend_from_slice(&self.version().to_le_bytes());
+        buf.extend_from_slice(&FLAGS.to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes()); // stored
+        buf.extend_from_slice(&self.time.0.to_le_bytes());
+        buf.extend_from_slice(&self.time.1.to_le_bytes());
+        buf.extend_from_slice(&crc.to_le_bytes());
+        buf.extend_from_slice(&size.to_le_bytes());
+        buf.extend_from_slice(&size.to_le_bytes());
+        buf.extend_from_slice(&(self.name.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&(self.central_extra_len() as u16).to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes()); // comment
+        buf.extend_from_slice(&0u16.to_le_bytes()); // disk number
+        buf.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+        buf.extend_from_slice(&(0o100644u32 << 16).to_le_bytes()); // regular file, rw-r--r--
+        buf.extend_from_slice(&offset.to_le_bytes());
+        buf.extend_from_slice(self.name.as_bytes());
+        if self.central_extra_len() > 0 {
+            buf.extend_from_slice(&1u16.to_le_bytes());
+            buf.extend_from_slice(&(self.central_extra_len() as u16 - 4).to_le_bytes());
+            if self.zip64() {
+                buf.extend_from_slice(&self.size.to_le_bytes());
+                buf.extend_from_slice(&self.size.to_le_bytes());
+            }
+            if self.zip64_offset() {
+                buf.extend_from_slice(&self.local_header_offset.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Converts a unix timestamp to DOS time and date (UTC), clamped to the DOS epoch
+fn dos_time(unix: u64) -> (u16, u16) {
+    let secs = unix % 86400;
+    let time = (((secs / 3600) << 11) | ((secs % 3600 / 60) << 5) | ((secs % 60) / 2)) as u16;
+    let (year, month, day) = civil_from_days((unix / 86400) as i64);
+
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let date = (((year - 1980).min(127) as u16) << 9) | ((month as u16) << 5) | day as u16;
+    (time, date)
+}
+
+struct CurrentFile {
+    file: File,
+    crc: Hasher,
+    remaining: u64,
+}
+
+/// Reader producing a ZIP archive of the given files
+pub struct ZipStream {
+    pending: VecDeque<ZipEntry>,
+    done: Vec<(ZipEntry, u32)>,
+    current: Option<(ZipEntry, CurrentFile)>,
+    buffer: Vec<u8>,
+    buffer_pos: usize,
+    central_directory_offset: u64,
+    total_size: u64,
+    finished: bool,
+    zip64_limit: u64,
+}
+
+impl ZipStream {
+    /// Prepares an archive of files given by their path on disk and name inside the archive.
+    /// Sizes are taken now, a file changing its size later results in a read error.
+    pub fn new(files: Vec<(PathBuf, String)>) -> io::Result<Self> {
+        Self::with_zip64_limit(files, ZIP64_LIMIT)
+    }
+
+    /// The tests can't use files large enough to need ZIP64, they lower the limit instead
+    fn with_zip64_limit(files: Vec<(PathBuf, String)>, zip64_limit: u64) -> io::Result<Self> {
+        let mut pending = VecDeque::new();
+        let mut offset = 0u64;
+        for (path, name) in files {
+            let meta = std::fs::metadata(&path)?;
+            let mtime = meta
+                .modified()
+                .ok()
+                .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let entry = ZipEntry {
+                path,
+                name,
+                size: meta.len(),
+                time: dos_time(mtime),
+                local_header_offset: offset,
+                zip64_limit,
+            };
+            offset += entry.local_header_len() + entry.size + entry.data_descriptor_len();
+            pending.push_back(entry);
+        }
+
+        let central_directory_offset = offset;
+        let central_directory_size: u64 = pending.iter().map(ZipEntry::central_header_len).sum();
+        let mut stream = ZipStream {
+            pending,
+            done: vec![],
+            current: None,
+            buffer: vec![],
+            buffer_pos: 0,
+            central_directory_offset,
+            total_size: 0,
+            finished: false,
+            zip64_limit,
+        };
+        stream.total_size = central_directory_offset
+            + central_directory_size
+            + stream.end_records(central_directory_size).len() as u64;
+        Ok(stream)
+    }
+
+    /// Exact length of the whole archive in bytes
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    fn entry_count(&self) -> u64 {
+        (self.pending.len() + self.done.len() + self.current.iter().count()) as u64
+    }
+
+    fn end_records(&self, central_directory_size: u64) -> Vec<u8> {
+        let count = self.entry_count();
+        let offset = self.central_directory_offset;
+        let zip64 = count >= 0xFFFF
+            || central_directory_size >= self.zip64_limit
+            || offset >= self.zip64_limit
+            || self.pending.iter().any(|e| e.version() == VERSION_ZIP64)
+            || self.done.iter().any(|(e, _)| e.version() == VERSION_ZIP64);
+
+        let mut buf = vec![];
+        if zip64 {
+            let zip64_end_offset = offset + central_directory_size;
+            buf.extend_from_slice(&0x06064b50u32.to_le_bytes());
+            buf.extend_from_slice(&44u64.to_le_bytes()); // size of the remaining record
+            buf.extend_from_slice(&(0x0300 | VERSION_ZIP64).to_le_bytes());
+            buf.extend_from_slice(&VERSION_ZIP64.to_le_bytes());
+            buf.extend_from_slice(&0u32.to_le_bytes()); // disk number
+            buf.extend_from_slice(&0u32.to_le_bytes()); // disk with the central directory
+            buf.extend_from_slice(&count.to_le_bytes());
+            buf.extend_from_slice(&count.to_le_bytes());
+            buf.extend_from_slice(&central_directory_size.to_le_bytes());
+            buf.extend_from_slice(&offset.to_le_bytes());
+
+            buf.extend_from_slice(&0x07064b50u32.to_le_bytes());
+            buf.extend_from_slice(&0u32.to_le_bytes()); // disk with the ZIP64 end record
+            buf.extend_from_slice(&zip64_end_offset.to_le_bytes());
+            buf.extend_from_slice(&1u32.to_le_bytes()); // total number of disks
+        }
+
+        buf.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes()); // disk number
+        buf.extend_from_slice(&0u16.to_le_bytes()); // disk with the central directory
+        buf.extend_from_slice(&(count.min(0xFFFF) as u16).to_le_bytes());
+        buf.extend_from_slice(&(count.min(0xFFFF) as u16).to_le_bytes());
+        for value in [central_directory_size, offset] {
+            let value = if value >= self.zip64_limit {
+                u32::MAX
+            } else {
+                value as u32
+            };
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        buf.extend_from_slice(&0u16.to_le_bytes()); // comment
+        buf
+    }
+
+    fn set_buffer(&mut self, buf: Vec<u8>) {
+        self.buffer = buf;
+        self.buffer_pos = 0;
+    }
+}
+
+impl Read for ZipStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        loop {
+            /* first send out any prepared headers */
+            if this.buffer_pos < this.buffer.len() {
+                let len = out.len().min(this.buffer.len() - this.buffer_pos);
+                out[..len].copy_from_slice(&this.buffer[this.buffer_pos..this.buffer_pos + len]);
+                this.buffer_pos += len;
+                return Poll::Ready(Ok(len));
+            }
+
+            /* then the content of the current file */
+            if let Some((entry, current)) = &mut this.current {
+                if current.remaining > 0 {
+                    let max = out
+                        .len()
+                        .min(current.remaining.min(usize::MAX as u64) as usize);
+                    let read = match Pin::new(&mut current.file).poll_read(cx, &mut out[..max]) {
+                        Poll::Ready(Ok(read)) => read,
+                        other => return other,
+                    };
+                    if read == 0 {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("file \"{}\" shrank while being archived", entry.name),
+                        )));
+                    }
+                    current.crc.update(&out[..read]);
+                    current.remaining -= read as u64;
+                    return Poll::Ready(Ok(read));
+                }
+
+                let (entry, current) = this.current.take().unwrap();
+                let crc = current.crc.finalize();
+                let mut buf = vec![];
+                entry.write_data_descriptor(&mut buf, crc);
+                this.set_buffer(buf);
+                this.done.push((entry, crc));
+                continue;
+            }
+
+            /* open the next file */
+            if let Some(entry) = this.pending.pop_front() {
+                let file = File::from(std::fs::File::open(&entry.path)?);
+                let mut buf = vec![];
+                entry.write_local_header(&mut buf);
+                this.set_buffer(buf);
+                let remaining = entry.size;
+                this.current = Some((
+                    entry,
+                    CurrentFile {
+                        file,
+                        crc: Hasher::new(),
+                        remaining,
+                    },
+                ));
+                continue;
+            }
+
+            /* and finally the central directory */
+            if !this.finished {
+                this.finished = true;
+                let mut buf = vec![];
+                for (entry, crc) in &this.done {
+                    entry.write_central_header(&mut buf, *crc);
+                }
+                let end = this.end_records(buf.len() as u64);
+                buf.extend_from_slice(&end);
+                this.set_buffer(buf);
+                continue;
+            }
+
+            return Poll::Ready(Ok(0));
+        }
+    }
+}
+
+#[test]
+fn test_dos_time() {
+    /* 2022-12-13 19:26:20 UTC */
+    let (time, date) = dos_time(1670959580);
+    assert_eq!(date, (42 << 9) | (12 << 5) | 13);
+    assert_eq!(time, (19 << 11) | (26 << 5) | 10);
+}
+
+/// Little endian number of `len` bytes at `pos`
+#[cfg(test)]
+fn read_le(zip: &[u8], pos: usize, len: usize) -> u64 {
+    zip[pos..pos + len]
+        .iter()
+        .rev()
+        .fold(0, |acc, b| (acc << 8) | *b as u64)
+}
+
+/// Reads the archive back starting from the central directory. The local headers, data
+/// descriptors and CRCs are checked, names and contents of the files are returned.
+#[cfg(test)]
+fn parse_archive(zip: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let end = zip.len() - 22;
+    assert_eq!(read_le(zip, end, 4), 0x06054b50);
+    let mut count = read_le(zip, end + 10, 2);
+    let mut offset = read_le(zip, end + 16, 4);
+    let mut central_directory_end = end;
+    if end >= 20 && read_le(zip, end - 20, 4) == 0x07064b50 {
+        let zip64_end = read_le(zip, end - 12, 8) as usize;
+        assert_eq!(read_le(zip, zip64_end, 4), 0x06064b50);
+        count = read_le(zip, zip64_end + 32, 8);
+        offset = read_le(zip, zip64_end + 48, 8);
+        central_directory_end = zip64_end;
+    }
+
+    let mut files = vec![];
+    let mut pos = offset as usize;
+    for _ in 0..count {
+        assert_eq!(read_le(zip, pos, 4), 0x02014b50);
+        let crc = read_le(zip, pos + 16, 4) as u32;
+        let mut size = read_le(zip, pos + 24, 4);
+        let name_len = read_le(zip, pos + 28, 2) as usize;
+        let extra_len = read_le(zip, pos + 30, 2) as usize;
+        let mut local_offset = read_le(zip, pos + 42, 4);
+        let name = String::from_utf8(zip[pos + 46..pos + 46 + name_len].to_vec()).unwrap();
+
+        /* the ZIP64 extra field has only the values which did not fit */
+        let zip64 = size == u32::MAX as u64;
+        let mut extra = pos + 46 + name_len;
+        if extra_len > 0 {
+            assert_eq!(read_le(zip, extra, 2), 1);
+            extra += 4;
+            if zip64 {
+                size = read_le(zip, extra, 8);
+                assert_eq!(read_le(zip, extra + 8, 8), size);
+                extra += 16;
+            }
+            if local_offset == u32::MAX as u64 {
+                local_offset = read_le(zip, extra, 8);
+            }
+        }
+        pos += 46 + name_len + extra_len;
+
+        let local = local_offset as usize;
+        assert_eq!(read_le(zip, local, 4), 0x04034b50);
+        assert_eq!(zip[local + 30..local + 30 + name_len], *name.as_bytes());
+        let data = local + 30 + name_len + read_le(zip, local + 28, 2) as usize;
+        let content = zip[data..data + size as usize].to_vec();
+        assert_eq!(crc32fast::hash(&content), crc);
+
+        let descriptor = data + size as usize;
+        assert_eq!(read_le(zip, descriptor, 4), 0x08074b50);
+        assert_eq!(read_le(zip, descriptor + 4, 4), crc as u64);
+        let size_len = if zip64 { 8 } else { 4 };
+        assert_eq!(read_le(zip, descriptor + 8, size_len), size);
+        assert_eq!(read_le(zip, descriptor + 8 + size_len, size_len), size);
+
+        files.push((name, content));
+    }
+    assert_eq!(pos, central_directory_end);
+    files
+}
+
+#[test]
+fn test_archive_can_be_read_back() {
+    use async_std::io::ReadExt;
+
+    let dir = std::env::temp_dir().join(format!("gimmedat-zip-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let contents: Vec<(String, Vec<u8>)> = vec![
+        ("hello.txt".to_owned(), b"hello world".to_vec()),
+        ("empty".to_owned(), vec![]),
+        (
+            "data.bin".to_owned(),
+            (0..=255).cycle().take(1000).collect(),
+        ),
+        ("žluťoučký kůň.txt".to_owned(), b"UTF-8 name".to_vec()),
+    ];
+    let files: Vec<(PathBuf, String)> = contents
+        .iter()
+        .enumerate()
+        .map(|(i, (name, content))| {
+            let path = dir.join(i.to_string());
+            std::fs::write(&path, content).unwrap();
+            (path, name.clone())
+        })
+        .collect();
+
+    /* with the lowered limit, both the large file and the offsets after it need ZIP64 */
+    for zip64_limit in [ZIP64_LIMIT, 500] {
+        let mut zip = ZipStream::with_zip64_limit(files.clone(), zip64_limit).unwrap();
+        let total_size = zip.total_size();
+        let mut streamed = vec![];
+        async_std::task::block_on(zip.read_to_end(&mut streamed)).unwrap();
+
+        assert_eq!(streamed.len() as u64, total_size);
+        assert_eq!(parse_archive(&streamed), contents);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}